@@ -0,0 +1,34 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Embed every mapping in `mapping/` so the built-in modes work without the directory.
+fn main() {
+    println!("cargo:rerun-if-changed=mapping");
+
+    let mut files: Vec<_> = fs::read_dir("mapping")
+        .expect("mapping directory")
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+
+    let mut code = String::from("const EMBEDDED_MAPPINGS: &[(&str, &str)] = &[\n");
+    for path in files {
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .expect("UTF-8 file name");
+        let full_path = fs::canonicalize(&path).expect("mapping file path");
+        code += &format!(
+            "    ({:?}, include_str!({:?})),\n",
+            file_name,
+            full_path.display().to_string()
+        );
+    }
+    code += "];\n";
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR");
+    fs::write(Path::new(&out_dir).join("embedded_mappings.rs"), code)
+        .expect("write embedded mappings");
+}
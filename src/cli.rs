@@ -1,18 +1,30 @@
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use crate::env::load_env_file;
+use crate::filters::FilterChain;
 use crate::launcher::{run_launcher, LauncherDefaults};
-use crate::mapping::{default_maps, load_mapping};
-use crate::outputs::keyboard::KeyboardOutput;
-use crate::serial::{run_serial_loop, SerialEvent, SerialOptions};
-use crate::types::{MappingConfig, OutputAdapter};
+use crate::mapping::{
+    default_maps, load_watched_mapping, parse_mapping_value, resolve_mapping, Binding,
+};
+use crate::outputs::keyboard::KeyboardDevice;
+use crate::outputs::mapper::ButtonMapper;
+use crate::outputs::MultiDevice;
+use crate::pipeline::{run_pipeline, Stage, StageSource};
+use crate::profiles::{parse_switch_buttons, ProfileSwitcher};
+use crate::reload::MappingWatcher;
+use crate::rules::RuleEngine;
+use crate::serial::SerialOptions;
+use crate::shutdown::{install_ctrlc_handler, install_panic_hook};
+use crate::types::{MappingConfig, OutputDevice, Outputs, Side};
 
 #[cfg(target_os = "windows")]
-use crate::outputs::x360::X360Output;
+use crate::outputs::x360::X360Device;
 
 #[derive(Parser, Debug)]
 #[command(name = "ps2iidx_controller", version = "1.0.0")]
@@ -38,6 +50,27 @@ pub struct CliArgs {
     #[arg(long = "map")]
     map: Option<String>,
 
+    /// Side to play on (1p, 2p)
+    #[arg(long = "side")]
+    side: Option<String>,
+
+    /// Override one button's entry, e.g. 4=F13 (repeatable)
+    #[arg(long = "bind", value_name = "BUTTON=ENTRY")]
+    bind: Vec<String>,
+
+    /// Profiles to cycle through by holding the switch buttons, e.g. iidx,popn,x360
+    #[arg(long = "profiles", value_delimiter = ',')]
+    profiles: Vec<String>,
+
+    /// Buttons that switch profile when held together (default: 0+3, Select+Start); they still
+    /// reach the game as normal inputs
+    #[arg(long = "switch-buttons", value_name = "BUTTONS")]
+    switch_buttons: Option<String>,
+
+    /// How long the switch buttons must be held, in milliseconds (default: 1000)
+    #[arg(long = "switch-hold", value_name = "MS")]
+    switch_hold: Option<u64>,
+
     /// Launch interactive port/mode selector
     #[arg(long = "launcher")]
     launcher: bool,
@@ -45,6 +78,18 @@ pub struct CliArgs {
     /// Enable debug mode
     #[arg(short = 'd', long = "debug")]
     debug: bool,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// Print a mapping with everything it extends merged in
+    Resolve {
+        /// Mapping mode (e.g. iidx, popn, x360) or JSON path; defaults to --map or --mode
+        mapping: Option<String>,
+    },
 }
 
 fn resolve_map_path(mode: &str, map_path: Option<&str>) -> Result<PathBuf, String> {
@@ -55,7 +100,7 @@ fn resolve_map_path(mode: &str, map_path: Option<&str>) -> Result<PathBuf, Strin
     }
     let maps = default_maps();
     let default_map = maps.get(mode).ok_or_else(|| {
-        let available: Vec<&str> = maps.keys().copied().collect();
+        let available: Vec<&str> = maps.keys().map(String::as_str).collect();
         format!(
             "Unknown mode \"{}\". Available: {}",
             mode,
@@ -65,6 +110,95 @@ fn resolve_map_path(mode: &str, map_path: Option<&str>) -> Result<PathBuf, Strin
     Ok(PathBuf::from(default_map))
 }
 
+#[cfg(target_os = "windows")]
+fn create_x360_device() -> Result<Box<dyn OutputDevice>, String> {
+    X360Device::new()
+        .map(|d| Box::new(d) as Box<dyn OutputDevice>)
+        .map_err(|e| format!("Failed to create X360 output: {}", e))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn create_x360_device() -> Result<Box<dyn OutputDevice>, String> {
+    Err("X360 output is only supported on Windows (requires ViGEmBus).".to_string())
+}
+
+/// Create every device the mapping drives (keyboard, virtual pad or both).
+fn create_devices(outputs: Outputs) -> Result<Box<dyn OutputDevice>, String> {
+    let mut devices: Vec<Box<dyn OutputDevice>> = Vec::new();
+    if outputs.keyboard {
+        devices.push(Box::new(KeyboardDevice::new()));
+    }
+    if outputs.pad {
+        devices.push(create_x360_device()?);
+    }
+    if devices.len() == 1 {
+        return Ok(devices.remove(0));
+    }
+    Ok(Box::new(MultiDevice::new(devices)))
+}
+
+/// The processing stage for a mapping: its filters, its rules, then the button mapper.
+fn build_stage(mapping: &MappingConfig, default_offset: u64, debug: bool) -> Stage {
+    // Rules see events after the mapping's filters have cleaned them up
+    let mut filters = FilterChain::from_config(mapping.filters(), debug);
+    if !mapping.rules().is_empty() {
+        filters.push(Box::new(RuleEngine::new(mapping.rules().to_vec(), debug)));
+    }
+    Stage {
+        filters,
+        adapter: Box::new(ButtonMapper::new(mapping, default_offset, debug)),
+        outputs: mapping.outputs(),
+    }
+}
+
+/// Load a mapping mode or path and mirror it for its side, returning the files it was read
+/// from. An explicit --side beats the mapping's own side, which beats DEFAULT_SIDE.
+fn load_on_side(
+    target: &str,
+    bindings: &[Binding],
+    arg_side: Option<Side>,
+    env_side: Option<Side>,
+) -> Result<(MappingConfig, Side, Vec<PathBuf>), String> {
+    let path = default_maps()
+        .remove(target)
+        .unwrap_or_else(|| target.to_string());
+    let (mut mapping, files) = load_watched_mapping(&path, bindings)?;
+    let side = arg_side.or(mapping.side).or(env_side).unwrap_or_default();
+    mapping.play_on(side)?;
+    Ok((mapping, side, files))
+}
+
+/// A profile's mapping file: a mode name, or else a path.
+fn resolve_profile(profile: &str) -> Result<PathBuf, String> {
+    if default_maps().contains_key(profile) || !Path::new(profile).is_file() {
+        return resolve_map_path(profile, None);
+    }
+    resolve_map_path(profile, Some(profile))
+}
+
+/// Print the mapping a mode or path resolves to, once it is known to load.
+fn print_resolved(target: &str, bindings: &[Binding]) -> Result<(), String> {
+    let path = default_maps()
+        .remove(target)
+        .unwrap_or_else(|| target.to_string());
+    let (merged, source_label) = resolve_mapping(&path, bindings)?;
+    parse_mapping_value(&merged, &source_label)?;
+    let text = serde_json::to_string_pretty(&merged).map_err(|e| e.to_string())?;
+    writeln!(std::io::stdout(), "{}", text).map_err(|e| format!("Failed to print mapping: {}", e))
+}
+
+/// `MAP_BIND_<button>=<entry>` variables as `--bind` values.
+fn env_binds() -> Vec<String> {
+    let mut binds: Vec<String> = std::env::vars()
+        .filter_map(|(name, value)| {
+            let button = name.strip_prefix("MAP_BIND_")?;
+            Some(format!("{}={}", button, value))
+        })
+        .collect();
+    binds.sort();
+    binds
+}
+
 fn env_var_or(name: &str, default: &str) -> String {
     std::env::var(name).unwrap_or_else(|_| default.to_string())
 }
@@ -75,6 +209,7 @@ fn env_var_opt(name: &str) -> Option<String> {
 
 pub fn run_cli() -> Result<(), String> {
     load_env_file(None);
+    install_panic_hook();
 
     let args = CliArgs::parse();
 
@@ -96,6 +231,18 @@ pub fn run_cli() -> Result<(), String> {
         env_var_or("DEFAULT_OFFSET", "0").parse().unwrap_or(0)
     });
 
+    let arg_side = args.side.as_deref().map(Side::parse).transpose()?;
+    let env_side = env_var_opt("DEFAULT_SIDE")
+        .map(|name| Side::parse(&name))
+        .transpose()?;
+
+    // --bind comes last so it wins over MAP_BIND_<button>
+    let bindings = env_binds()
+        .iter()
+        .chain(&args.bind)
+        .map(|spec| Binding::parse(spec))
+        .collect::<Result<Vec<_>, _>>()?;
+
     let debug = args.debug || env_var_or("DEFAULT_DEBUG", "0") == "1";
 
     let mut port = default_port;
@@ -103,6 +250,11 @@ pub fn run_cli() -> Result<(), String> {
     let mut mode = default_mode;
     let mut map_path = args.map.clone().or_else(|| env_var_opt("DEFAULT_MAP"));
 
+    if let Some(CliCommand::Resolve { mapping }) = &args.command {
+        let target = mapping.as_ref().or(map_path.as_ref()).unwrap_or(&mode);
+        return print_resolved(target, &bindings);
+    }
+
     let should_launch = args.launcher || port.is_none();
 
     if should_launch {
@@ -123,7 +275,40 @@ pub fn run_cli() -> Result<(), String> {
         port.ok_or("Error: COM port must be specified (or use --launcher / DEFAULT_PORT).")?;
 
     let resolved_map_path = resolve_map_path(&mode, map_path.as_deref())?;
-    let mapping = load_mapping(resolved_map_path.to_str().unwrap_or(""))?;
+    let map_file = resolved_map_path.to_str().unwrap_or("").to_string();
+
+    let profiles = if args.profiles.is_empty() {
+        env_var_opt("DEFAULT_PROFILES")
+            .map(|list| list.split(',').map(|p| p.trim().to_string()).collect())
+            .unwrap_or_default()
+    } else {
+        args.profiles.clone()
+    };
+    let profile_paths = profiles
+        .iter()
+        .map(|profile| resolve_profile(profile))
+        .collect::<Result<Vec<_>, _>>()?;
+    let switch_buttons = parse_switch_buttons(
+        &args
+            .switch_buttons
+            .clone()
+            .unwrap_or_else(|| env_var_or("DEFAULT_SWITCH_BUTTONS", "0+3")),
+    )?;
+    let switch_hold = args.switch_hold.unwrap_or_else(|| {
+        env_var_or("DEFAULT_SWITCH_HOLD", "1000")
+            .parse()
+            .unwrap_or(1000)
+    });
+
+    let (mapping, side, files) = load_on_side(&map_file, &bindings, arg_side, env_side)?;
+    // The starting mapping, also when it is listed among the profiles
+    let starting: Vec<String> = profiles
+        .iter()
+        .zip(&profile_paths)
+        .filter(|(_, path)| **path == resolved_map_path)
+        .map(|(profile, _)| profile.clone())
+        .chain([map_file.clone()])
+        .collect();
 
     println!(
         "Mapping: {}",
@@ -132,66 +317,70 @@ pub fn run_cli() -> Result<(), String> {
             .unwrap_or(resolved_map_path.to_str().unwrap_or(""))
     );
     println!("Output: {:?}", mapping.output_type());
+    println!("Side: {}", side);
+    if !bindings.is_empty() {
+        let specs: Vec<&str> = bindings.iter().map(|b| b.spec.as_str()).collect();
+        println!("Bindings: {}", specs.join(", "));
+    }
+    if !profiles.is_empty() {
+        println!(
+            "Profiles: {} (hold {} for {} ms to switch)",
+            profiles.join(", "),
+            switch_buttons
+                .iter()
+                .map(u8::to_string)
+                .collect::<Vec<_>>()
+                .join("+"),
+            switch_hold
+        );
+    }
     println!("Baud rate: {}", baud_rate);
 
     // Setup Ctrl+C handler
     let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        println!("\nClosing...");
-        r.store(false, Ordering::SeqCst);
-    })
-    .map_err(|e| format!("Failed to set Ctrl+C handler: {}", e))?;
+    install_ctrlc_handler(running.clone())?;
 
     let serial_opts = SerialOptions {
         path: port,
         baud_rate,
         debug,
+        running,
     };
 
-    match mapping {
-        MappingConfig::Keyboard(km) => {
-            let mut kb = KeyboardOutput::new(km, default_offset, debug);
-            let running_ref = running.clone();
-            run_serial_loop(&serial_opts, |event| match event {
-                SerialEvent::Button(btn) => {
-                    kb.handle_button(&btn);
-                }
-                SerialEvent::Tick => {
-                    kb.process_tap_releases();
-                    if !running_ref.load(Ordering::SeqCst) {
-                        kb.shutdown();
-                        std::process::exit(0);
-                    }
-                }
-            })?;
-        }
-        MappingConfig::X360(_xm) => {
-            #[cfg(target_os = "windows")]
-            {
-                let mut x360 = X360Output::new(_xm, default_offset, debug)
-                    .map_err(|e| format!("Failed to create X360 output: {}", e))?;
-                let running_ref = running.clone();
-                run_serial_loop(&serial_opts, |event| match event {
-                    SerialEvent::Button(btn) => {
-                        x360.handle_button(&btn);
-                    }
-                    SerialEvent::Tick => {
-                        if !running_ref.load(Ordering::SeqCst) {
-                            x360.shutdown();
-                            std::process::exit(0);
-                        }
-                    }
-                })?;
-            }
-            #[cfg(not(target_os = "windows"))]
-            {
-                return Err(
-                    "X360 output is only supported on Windows (requires ViGEmBus).".to_string(),
-                );
-            }
-        }
-    }
+    // Filters and mapping logic run in the processing stage, the devices on the output thread;
+    // held inputs are released however the serial loop ends
+    let stage = build_stage(&mapping, default_offset, debug);
+    let create_device = Box::new(create_devices);
+
+    // Reloads and profile switches swap the stage between events, and the devices with it
+    let watcher = MappingWatcher::new(
+        &map_file,
+        files,
+        Box::new(move |target| {
+            // Bindings and side were given for the starting mapping; other profiles play as
+            // their files say
+            let (mapping, _, files) = if starting.iter().any(|s| s == target) {
+                load_on_side(target, &bindings, arg_side, env_side)?
+            } else {
+                load_on_side(target, &[], None, None)?
+            };
+            Ok((build_stage(&mapping, default_offset, debug), files))
+        }),
+    );
+    let source: Box<dyn StageSource> = if profiles.is_empty() {
+        Box::new(watcher)
+    } else {
+        let current = profile_paths.iter().position(|p| *p == resolved_map_path);
+        Box::new(ProfileSwitcher::new(
+            profiles,
+            current,
+            switch_buttons,
+            Duration::from_millis(switch_hold),
+            watcher,
+        ))
+    };
+
+    run_pipeline(serial_opts, stage, Some(source), create_device)?;
 
     Ok(())
 }
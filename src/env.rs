@@ -43,6 +43,7 @@ pub fn load_env_file(file_path: Option<&str>) {
     }
 }
 
+#[allow(clippy::collapsible_if)]
 fn strip_quotes(value: &str) -> &str {
     let trimmed = value.trim();
     if (trimmed.starts_with('"') && trimmed.ends_with('"'))
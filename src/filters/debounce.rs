@@ -0,0 +1,258 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use super::EventFilter;
+use crate::types::{ButtonEvent, DebounceMode};
+
+#[derive(Default)]
+struct ButtonState {
+    /// State last passed on
+    stable: bool,
+    /// State last reported by the switch
+    raw: bool,
+    /// Eager: end of the lockout after the last change. Deferred: when a pending change settles.
+    until: Option<Instant>,
+}
+
+/// Suppresses switch chatter per button.
+///
+/// - eager: a change passes immediately, then further changes are ignored for the window;
+///   if the switch ends up in the other state, that is passed on when the window closes
+/// - deferred: a change passes only once the switch has held it for the whole window
+pub struct Debounce {
+    mode: DebounceMode,
+    default_window: Duration,
+    windows: HashMap<u8, Duration>,
+    states: HashMap<u8, ButtonState>,
+    /// Suppressed events per button
+    bounces: BTreeMap<u8, u64>,
+    debug: bool,
+}
+
+impl Debounce {
+    pub fn new(
+        mode: DebounceMode,
+        default_ms: u64,
+        buttons: &HashMap<u8, u64>,
+        debug: bool,
+    ) -> Self {
+        Self {
+            mode,
+            default_window: Duration::from_millis(default_ms),
+            windows: buttons
+                .iter()
+                .map(|(&id, &ms)| (id, Duration::from_millis(ms)))
+                .collect(),
+            states: HashMap::new(),
+            bounces: BTreeMap::new(),
+            debug,
+        }
+    }
+
+    fn window(&self, id: u8) -> Duration {
+        self.windows
+            .get(&id)
+            .copied()
+            .unwrap_or(self.default_window)
+    }
+
+    fn suppress(&mut self, id: u8, count: u64) {
+        *self.bounces.entry(id).or_default() += count;
+        if self.debug {
+            println!("[debounce] button {} bounce suppressed", id);
+        }
+    }
+
+    fn eager(&mut self, event: ButtonEvent, window: Duration, out: &mut Vec<ButtonEvent>) {
+        // A correction due before this event has to go out first
+        self.poll(event.at, out);
+
+        let state = self.states.entry(event.id).or_default();
+        state.raw = event.pressed;
+        if state.until.is_some_and(|until| event.at < until) {
+            self.suppress(event.id, 1);
+            return;
+        }
+        if event.pressed != state.stable {
+            state.stable = event.pressed;
+            state.until = Some(event.at + window);
+            out.push(event);
+        }
+    }
+
+    fn deferred(&mut self, event: ButtonEvent, window: Duration) {
+        let state = self.states.entry(event.id).or_default();
+        state.raw = event.pressed;
+        if event.pressed == state.stable {
+            // Back to the stable state before the pending change settled
+            if state.until.take().is_some() {
+                self.suppress(event.id, 2);
+            }
+        } else if state.until.is_none() {
+            state.until = Some(event.at + window);
+        }
+    }
+}
+
+impl EventFilter for Debounce {
+    fn on_event(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        let window = self.window(event.id);
+        if window.is_zero() {
+            out.push(event);
+            return;
+        }
+        match self.mode {
+            DebounceMode::Eager => self.eager(event, window, out),
+            DebounceMode::Deferred => self.deferred(event, window),
+        }
+    }
+
+    fn poll(&mut self, now: Instant, out: &mut Vec<ButtonEvent>) {
+        let mut due: Vec<(Instant, u8)> = Vec::new();
+        for (&id, state) in &mut self.states {
+            let Some(until) = state.until.filter(|until| *until <= now) else {
+                continue;
+            };
+            state.until = None;
+            if state.raw != state.stable {
+                state.stable = state.raw;
+                due.push((until, id));
+            }
+        }
+        due.sort();
+
+        for (at, id) in due {
+            let window = self.window(id);
+            let state = self.states.get_mut(&id).unwrap();
+            if self.mode == DebounceMode::Eager {
+                // The settled change is a new edge, so it gets its own lockout
+                state.until = Some(at + window);
+            }
+            out.push(ButtonEvent {
+                id,
+                pressed: state.stable,
+                at,
+            });
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        // Eager lockouts only need a wake-up if the switch changed state during them
+        self.states
+            .values()
+            .filter(|s| self.mode == DebounceMode::Deferred || s.raw != s.stable)
+            .filter_map(|s| s.until)
+            .min()
+    }
+
+    fn reset(&mut self) {
+        self.states.clear();
+    }
+
+    fn summary(&self) -> Option<String> {
+        let total: u64 = self.bounces.values().sum();
+        let per_button: Vec<String> = self
+            .bounces
+            .iter()
+            .map(|(id, count)| format!("button {}: {}", id, count))
+            .collect();
+        Some(if total == 0 {
+            "debounce: no bounces suppressed".to_string()
+        } else {
+            format!(
+                "debounce: {} bounces suppressed ({})",
+                total,
+                per_button.join(", ")
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: u8, pressed: bool, at: Instant) -> ButtonEvent {
+        ButtonEvent { id, pressed, at }
+    }
+
+    fn states(events: &[ButtonEvent]) -> Vec<bool> {
+        events.iter().map(|e| e.pressed).collect()
+    }
+
+    #[test]
+    fn eager_passes_first_edge_and_suppresses_chatter() {
+        let mut filter = Debounce::new(DebounceMode::Eager, 5, &HashMap::new(), false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        filter.on_event(event(12, true, start), &mut out);
+        filter.on_event(event(12, false, start + Duration::from_millis(1)), &mut out);
+        filter.on_event(event(12, true, start + Duration::from_millis(2)), &mut out);
+        assert_eq!(states(&out), vec![true]);
+        assert_eq!(filter.next_deadline(), None);
+
+        filter.on_event(
+            event(12, false, start + Duration::from_millis(30)),
+            &mut out,
+        );
+        assert_eq!(states(&out), vec![true, false]);
+        assert_eq!(filter.bounces.get(&12), Some(&2));
+    }
+
+    #[test]
+    fn eager_passes_on_state_changed_during_lockout() {
+        let mut filter = Debounce::new(DebounceMode::Eager, 5, &HashMap::new(), false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        filter.on_event(event(3, true, start), &mut out);
+        filter.on_event(event(3, false, start + Duration::from_millis(2)), &mut out);
+        assert_eq!(
+            filter.next_deadline(),
+            Some(start + Duration::from_millis(5))
+        );
+
+        filter.poll(start + Duration::from_millis(5), &mut out);
+        assert_eq!(states(&out), vec![true, false]);
+        assert_eq!(out[1].at, start + Duration::from_millis(5));
+    }
+
+    #[test]
+    fn deferred_waits_for_stable_state() {
+        let mut filter = Debounce::new(DebounceMode::Deferred, 5, &HashMap::new(), false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        filter.on_event(event(12, true, start), &mut out);
+        filter.on_event(event(12, false, start + Duration::from_millis(1)), &mut out);
+        filter.on_event(event(12, true, start + Duration::from_millis(2)), &mut out);
+        assert!(out.is_empty());
+        assert_eq!(
+            filter.next_deadline(),
+            Some(start + Duration::from_millis(7))
+        );
+
+        filter.poll(start + Duration::from_millis(7), &mut out);
+        assert_eq!(states(&out), vec![true]);
+        assert_eq!(filter.bounces.get(&12), Some(&2));
+    }
+
+    #[test]
+    fn per_button_window_overrides_default() {
+        let buttons = HashMap::from([(12, 5)]);
+        let mut filter = Debounce::new(DebounceMode::Eager, 0, &buttons, false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        filter.on_event(event(4, true, start), &mut out);
+        filter.on_event(event(4, false, start), &mut out);
+        filter.on_event(event(12, true, start), &mut out);
+        filter.on_event(event(12, false, start), &mut out);
+        assert_eq!(states(&out), vec![true, false, true]);
+        assert_eq!(
+            filter.summary().unwrap(),
+            "debounce: 1 bounces suppressed (button 12: 1)"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::EventFilter;
+use crate::types::ButtonEvent;
+
+#[derive(Default)]
+struct ButtonState {
+    /// When the press last passed on went out, while the button is down
+    down_since: Option<Instant>,
+    /// When the last event passed on went out; later events never overtake it
+    last_out: Option<Instant>,
+    /// Events waiting behind a deferred release, in arrival order
+    queue: VecDeque<ButtonEvent>,
+}
+
+impl ButtonState {
+    /// When the front of the queue may go out.
+    fn front_due(&self, min: Duration) -> Option<Instant> {
+        let event = self.queue.front()?;
+        let mut at = event.at;
+        if let Some(last) = self.last_out {
+            at = at.max(last);
+        }
+        if let (false, Some(since)) = (event.pressed, self.down_since) {
+            at = at.max(since + min);
+        }
+        Some(at)
+    }
+}
+
+/// Keeps every press down for a minimum time, so a press and release that arrive
+/// back to back still reach games that poll once per frame.
+///
+/// A release that comes too early is held back until the minimum has passed; events of the
+/// same button that arrive meanwhile wait behind it.
+pub struct MinHold {
+    default_min: Duration,
+    mins: HashMap<u8, Duration>,
+    states: HashMap<u8, ButtonState>,
+    /// Deferred releases per button
+    deferred: BTreeMap<u8, u64>,
+    debug: bool,
+}
+
+impl MinHold {
+    pub fn new(default_ms: u64, buttons: &HashMap<u8, u64>, debug: bool) -> Self {
+        Self {
+            default_min: Duration::from_millis(default_ms),
+            mins: buttons
+                .iter()
+                .map(|(&id, &ms)| (id, Duration::from_millis(ms)))
+                .collect(),
+            states: HashMap::new(),
+            deferred: BTreeMap::new(),
+            debug,
+        }
+    }
+
+    fn min(&self, id: u8) -> Duration {
+        self.mins.get(&id).copied().unwrap_or(self.default_min)
+    }
+
+    /// Pass on the queued events of `id` that may go out at `now`.
+    fn drain(&mut self, id: u8, now: Instant, out: &mut Vec<(Instant, ButtonEvent)>) {
+        let min = self.min(id);
+        let Some(state) = self.states.get_mut(&id) else {
+            return;
+        };
+        while let Some(at) = state.front_due(min).filter(|at| *at <= now) {
+            let event = state.queue.pop_front().expect("front_due saw an event");
+            if !event.pressed && at > event.at {
+                *self.deferred.entry(id).or_default() += 1;
+                if self.debug {
+                    println!(
+                        "[min hold] button {} release deferred {:.2} ms",
+                        id,
+                        (at - event.at).as_secs_f64() * 1000.0
+                    );
+                }
+            }
+            state.down_since = event.pressed.then_some(at);
+            state.last_out = Some(at);
+            out.push((at, ButtonEvent { at, ..event }));
+        }
+    }
+}
+
+impl EventFilter for MinHold {
+    fn on_event(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        if self.min(event.id).is_zero() {
+            out.push(event);
+            return;
+        }
+        // Deferred releases due before this event go out first
+        self.poll(event.at, out);
+
+        let (id, at) = (event.id, event.at);
+        self.states.entry(id).or_default().queue.push_back(event);
+        let mut passed = Vec::new();
+        self.drain(id, at, &mut passed);
+        out.extend(passed.into_iter().map(|(_, event)| event));
+    }
+
+    fn poll(&mut self, now: Instant, out: &mut Vec<ButtonEvent>) {
+        let ids: Vec<u8> = self
+            .states
+            .iter()
+            .filter(|(_, state)| !state.queue.is_empty())
+            .map(|(&id, _)| id)
+            .collect();
+        let mut due = Vec::new();
+        for id in ids {
+            self.drain(id, now, &mut due);
+        }
+        due.sort_by_key(|(at, _)| *at);
+        out.extend(due.into_iter().map(|(_, event)| event));
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.states
+            .iter()
+            .filter_map(|(&id, state)| state.front_due(self.min(id)))
+            .min()
+    }
+
+    fn reset(&mut self) {
+        self.states.clear();
+    }
+
+    fn summary(&self) -> Option<String> {
+        let total: u64 = self.deferred.values().sum();
+        let per_button: Vec<String> = self
+            .deferred
+            .iter()
+            .map(|(id, count)| format!("button {}: {}", id, count))
+            .collect();
+        Some(if total == 0 {
+            "min hold: no releases deferred".to_string()
+        } else {
+            format!(
+                "min hold: {} releases deferred ({})",
+                total,
+                per_button.join(", ")
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: u8, pressed: bool, at: Instant) -> ButtonEvent {
+        ButtonEvent { id, pressed, at }
+    }
+
+    #[test]
+    fn early_release_waits_for_minimum() {
+        let mut filter = MinHold::new(20, &HashMap::new(), false);
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut out = Vec::new();
+
+        filter.on_event(event(3, true, start), &mut out);
+        filter.on_event(event(3, false, ms(1)), &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(filter.next_deadline(), Some(ms(20)));
+
+        filter.poll(ms(20), &mut out);
+        assert_eq!(out.len(), 2);
+        assert!(!out[1].pressed);
+        assert_eq!(out[1].at, ms(20));
+        assert_eq!(filter.deferred.get(&3), Some(&1));
+
+        // Held long enough: passes straight through
+        filter.on_event(event(3, true, ms(100)), &mut out);
+        filter.on_event(event(3, false, ms(150)), &mut out);
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[3].at, ms(150));
+    }
+
+    #[test]
+    fn repeat_press_waits_behind_deferred_release() {
+        let buttons = HashMap::from([(5, 10)]);
+        let mut filter = MinHold::new(0, &buttons, false);
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut out = Vec::new();
+
+        filter.on_event(event(5, true, start), &mut out);
+        filter.on_event(event(5, false, ms(1)), &mut out);
+        filter.on_event(event(5, true, ms(2)), &mut out);
+        filter.on_event(event(5, false, ms(3)), &mut out);
+        filter.poll(ms(30), &mut out);
+        let edges: Vec<(bool, Instant)> = out.iter().map(|e| (e.pressed, e.at)).collect();
+        assert_eq!(
+            edges,
+            vec![
+                (true, start),
+                (false, ms(10)),
+                (true, ms(10)),
+                (false, ms(20))
+            ]
+        );
+
+        // Buttons without a minimum are untouched
+        filter.on_event(event(6, true, ms(40)), &mut out);
+        filter.on_event(event(6, false, ms(40)), &mut out);
+        assert_eq!(out.len(), 6);
+    }
+}
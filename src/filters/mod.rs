@@ -0,0 +1,207 @@
+mod debounce;
+mod min_hold;
+mod remap;
+mod socd;
+mod turntable;
+
+use std::time::Instant;
+
+use crate::types::{ButtonEvent, FilterConfig};
+
+pub use debounce::Debounce;
+pub use min_hold::MinHold;
+pub use remap::Remap;
+pub use socd::Socd;
+pub use turntable::Turntable;
+
+/// How long a turntable direction stays held after its last pulse, unless configured.
+const DEFAULT_TURNTABLE_IDLE_MS: u64 = 80;
+
+/// One stage of the event filter chain, run in the processing stage before the output adapter.
+/// A filter may drop, rewrite, delay or add button events.
+pub trait EventFilter {
+    fn on_event(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>);
+    /// Emit delayed events that are due at `now`.
+    fn poll(&mut self, _now: Instant, _out: &mut Vec<ButtonEvent>) {}
+    /// When `poll` next has something to do.
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+    /// Statistics worth reporting on shutdown in debug mode.
+    fn summary(&self) -> Option<String> {
+        None
+    }
+    /// Forget held buttons and pending events, e.g. once the serial link is lost and their
+    /// releases may never arrive. Statistics are kept.
+    fn reset(&mut self) {}
+}
+
+/// Filters in mapping order; each stage sees only what the previous stages let through.
+pub struct FilterChain {
+    stages: Vec<Box<dyn EventFilter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Build the chain described by a mapping's `"filters"` list.
+    pub fn from_config(configs: &[FilterConfig], debug: bool) -> Self {
+        let mut chain = Self::new();
+        for config in configs {
+            match config {
+                FilterConfig::Remap { buttons } => {
+                    chain.push(Box::new(Remap::new(buttons.clone())))
+                }
+                FilterConfig::Debounce { mode, ms, buttons } => {
+                    chain.push(Box::new(Debounce::new(*mode, *ms, buttons, debug)))
+                }
+                FilterConfig::MinHold { ms, buttons } => {
+                    chain.push(Box::new(MinHold::new(*ms, buttons, debug)))
+                }
+                FilterConfig::Socd { mode, pairs } => {
+                    chain.push(Box::new(Socd::new(*mode, pairs, debug)))
+                }
+                FilterConfig::Turntable {
+                    up,
+                    down,
+                    idle_ms,
+                    pass_while_held,
+                } => chain.push(Box::new(Turntable::new(
+                    *up,
+                    *down,
+                    idle_ms.unwrap_or(DEFAULT_TURNTABLE_IDLE_MS),
+                    pass_while_held,
+                    debug,
+                ))),
+            }
+        }
+        chain
+    }
+
+    pub fn push(&mut self, stage: Box<dyn EventFilter>) {
+        self.stages.push(stage);
+    }
+
+    pub fn on_event(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        self.run_from(0, vec![event], out);
+    }
+
+    /// Poll every stage, passing what each emits through the stages after it.
+    pub fn poll(&mut self, now: Instant, out: &mut Vec<ButtonEvent>) {
+        for index in 0..self.stages.len() {
+            let mut emitted = Vec::new();
+            self.stages[index].poll(now, &mut emitted);
+            if !emitted.is_empty() {
+                self.run_from(index + 1, emitted, out);
+            }
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.stages.iter().filter_map(|s| s.next_deadline()).min()
+    }
+
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+    }
+
+    pub fn summaries(&self) -> Vec<String> {
+        self.stages.iter().filter_map(|s| s.summary()).collect()
+    }
+
+    fn run_from(&mut self, start: usize, mut events: Vec<ButtonEvent>, out: &mut Vec<ButtonEvent>) {
+        for stage in &mut self.stages[start..] {
+            let mut next = Vec::new();
+            for event in events {
+                stage.on_event(event, &mut next);
+            }
+            events = next;
+        }
+        out.extend(events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    /// Holds every event back for a fixed delay.
+    struct Delay {
+        delay: Duration,
+        pending: Vec<ButtonEvent>,
+    }
+
+    impl EventFilter for Delay {
+        fn on_event(&mut self, mut event: ButtonEvent, _out: &mut Vec<ButtonEvent>) {
+            event.at += self.delay;
+            self.pending.push(event);
+        }
+
+        fn poll(&mut self, now: Instant, out: &mut Vec<ButtonEvent>) {
+            let (due, pending) = self.pending.drain(..).partition(|e| e.at <= now);
+            self.pending = pending;
+            out.extend::<Vec<_>>(due);
+        }
+
+        fn next_deadline(&self) -> Option<Instant> {
+            self.pending.iter().map(|e| e.at).min()
+        }
+    }
+
+    fn ids(events: &[ButtonEvent]) -> Vec<u8> {
+        events.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn empty_chain_passes_events_through() {
+        let mut chain = FilterChain::new();
+        let mut out = Vec::new();
+        chain.on_event(
+            ButtonEvent {
+                id: 4,
+                pressed: true,
+                at: Instant::now(),
+            },
+            &mut out,
+        );
+        assert_eq!(ids(&out), vec![4]);
+        assert_eq!(chain.next_deadline(), None);
+    }
+
+    #[test]
+    fn polled_events_pass_through_later_stages_only() {
+        let start = Instant::now();
+        let mut chain = FilterChain::new();
+        chain.push(Box::new(Remap::new(HashMap::from([(1, 2)]))));
+        chain.push(Box::new(Delay {
+            delay: Duration::from_millis(5),
+            pending: Vec::new(),
+        }));
+        chain.push(Box::new(Remap::new(HashMap::from([(2, 3)]))));
+
+        let mut out = Vec::new();
+        chain.on_event(
+            ButtonEvent {
+                id: 1,
+                pressed: true,
+                at: start,
+            },
+            &mut out,
+        );
+        assert!(out.is_empty());
+        assert_eq!(
+            chain.next_deadline(),
+            Some(start + Duration::from_millis(5))
+        );
+
+        chain.poll(start + Duration::from_millis(5), &mut out);
+        assert_eq!(ids(&out), vec![3]);
+        assert_eq!(chain.next_deadline(), None);
+    }
+}
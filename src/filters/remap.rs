@@ -0,0 +1,25 @@
+use std::collections::HashMap;
+
+use super::EventFilter;
+use crate::types::ButtonEvent;
+
+/// Renumber buttons, e.g. to swap two switches without editing every output entry.
+/// Buttons not listed pass through unchanged.
+pub struct Remap {
+    buttons: HashMap<u8, u8>,
+}
+
+impl Remap {
+    pub fn new(buttons: HashMap<u8, u8>) -> Self {
+        Self { buttons }
+    }
+}
+
+impl EventFilter for Remap {
+    fn on_event(&mut self, mut event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        if let Some(&id) = self.buttons.get(&event.id) {
+            event.id = id;
+        }
+        out.push(event);
+    }
+}
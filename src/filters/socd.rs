@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use super::EventFilter;
+use crate::types::{ButtonEvent, SocdMode};
+
+/// One pair of opposing buttons.
+struct Pair {
+    buttons: [u8; 2],
+    /// Physically held, in press order (earliest first)
+    held: Vec<u8>,
+    /// Passed on as pressed
+    active: [bool; 2],
+}
+
+/// Resolves simultaneous opposing inputs (SOCD), e.g. turntable up and down or left and
+/// right, so at most one button of each declared pair is ever passed on as pressed.
+pub struct Socd {
+    mode: SocdMode,
+    pairs: Vec<Pair>,
+    /// Button id -> index into `pairs`
+    lookup: HashMap<u8, usize>,
+    debug: bool,
+}
+
+impl Socd {
+    pub fn new(mode: SocdMode, pairs: &[(u8, u8)], debug: bool) -> Self {
+        let mut lookup = HashMap::new();
+        for (index, &(a, b)) in pairs.iter().enumerate() {
+            lookup.insert(a, index);
+            lookup.insert(b, index);
+        }
+        Self {
+            mode,
+            pairs: pairs
+                .iter()
+                .map(|&(a, b)| Pair {
+                    buttons: [a, b],
+                    held: Vec::new(),
+                    active: [false; 2],
+                })
+                .collect(),
+            lookup,
+            debug,
+        }
+    }
+}
+
+impl EventFilter for Socd {
+    fn on_event(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        let Some(&index) = self.lookup.get(&event.id) else {
+            out.push(event);
+            return;
+        };
+        let pair = &mut self.pairs[index];
+        pair.held.retain(|&id| id != event.id);
+        if event.pressed {
+            pair.held.push(event.id);
+        }
+
+        let winner = match (pair.held.as_slice(), self.mode) {
+            ([], _) => None,
+            ([only], _) => Some(*only),
+            ([first, ..], SocdMode::FirstWins) => Some(*first),
+            ([.., last], SocdMode::LastWins) => Some(*last),
+            (_, SocdMode::Neutral) => None,
+            (_, SocdMode::Priority) => Some(pair.buttons[0]),
+        };
+        if self.debug && pair.held.len() == 2 {
+            match winner {
+                Some(id) => println!("[socd] buttons {:?} held, {} wins", pair.buttons, id),
+                None => println!("[socd] buttons {:?} held, neutral", pair.buttons),
+            }
+        }
+
+        // Release the loser before pressing the winner
+        let wanted = pair.buttons.map(|id| Some(id) == winner);
+        for pressed in [false, true] {
+            for (side, &want) in wanted.iter().enumerate() {
+                if want == pressed && pair.active[side] != pressed {
+                    pair.active[side] = pressed;
+                    out.push(ButtonEvent {
+                        id: pair.buttons[side],
+                        pressed,
+                        at: event.at,
+                    });
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for pair in &mut self.pairs {
+            pair.held.clear();
+            pair.active = [false; 2];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn run(mode: SocdMode, events: &[(u8, bool)]) -> Vec<(u8, bool)> {
+        let mut filter = Socd::new(mode, &[(4, 6)], false);
+        let at = Instant::now();
+        let mut out = Vec::new();
+        for &(id, pressed) in events {
+            filter.on_event(ButtonEvent { id, pressed, at }, &mut out);
+        }
+        out.into_iter().map(|e| (e.id, e.pressed)).collect()
+    }
+
+    const OVERLAP: [(u8, bool); 4] = [(4, true), (6, true), (6, false), (4, false)];
+
+    #[test]
+    fn last_input_wins_and_restores_held_button() {
+        assert_eq!(
+            run(SocdMode::LastWins, &OVERLAP),
+            vec![
+                (4, true),
+                (4, false),
+                (6, true),
+                (6, false),
+                (4, true),
+                (4, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn first_input_neutral_and_priority() {
+        assert_eq!(
+            run(SocdMode::FirstWins, &OVERLAP),
+            vec![(4, true), (4, false)]
+        );
+        assert_eq!(
+            run(SocdMode::Neutral, &OVERLAP),
+            vec![(4, true), (4, false), (4, true), (4, false)]
+        );
+        assert_eq!(
+            run(
+                SocdMode::Priority,
+                &[(6, true), (4, true), (4, false), (6, false)]
+            ),
+            vec![
+                (6, true),
+                (6, false),
+                (4, true),
+                (4, false),
+                (6, true),
+                (6, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_forgets_buttons_held_before() {
+        let mut filter = Socd::new(SocdMode::FirstWins, &[(4, 6)], false);
+        let at = Instant::now();
+        let press = |id| ButtonEvent {
+            id,
+            pressed: true,
+            at,
+        };
+        let mut out = Vec::new();
+        filter.on_event(press(4), &mut out);
+
+        // The link dropped with 4 held; its release never arrives
+        filter.reset();
+        out.clear();
+        filter.on_event(press(6), &mut out);
+        let edges: Vec<(u8, bool)> = out.iter().map(|e| (e.id, e.pressed)).collect();
+        assert_eq!(edges, vec![(6, true)]);
+    }
+
+    #[test]
+    fn other_buttons_pass_through() {
+        assert_eq!(run(SocdMode::Neutral, &[(1, true)]), vec![(1, true)]);
+    }
+}
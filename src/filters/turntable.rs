@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use super::EventFilter;
+use crate::types::ButtonEvent;
+
+/// Turns the turntable's pulse trains into a sustained hold per direction.
+///
+/// The PS2 turntable reports a scratch as short presses of one button per direction.
+/// The first pulse presses that direction, a pulse in the other direction switches over
+/// immediately, and the hold is released once no pulse has arrived for the idle timeout.
+///
+/// While one of the `pass_while_held` buttons is held, pulses are passed on as they are, so
+/// rules that act on each press (e.g. taps while the effector is held) still see every pulse.
+pub struct Turntable {
+    up: u8,
+    down: u8,
+    idle: Duration,
+    pass_while_held: Vec<u8>,
+    /// `pass_while_held` buttons currently down
+    passing: HashSet<u8>,
+    /// Pulses passed on as pressed, whose release has to follow them
+    passed: HashSet<u8>,
+    /// Direction currently held downstream
+    held: Option<u8>,
+    /// Whether the held direction's button is physically down right now
+    pulse_down: bool,
+    /// When the hold ends unless another pulse arrives
+    release_at: Option<Instant>,
+    debug: bool,
+}
+
+impl Turntable {
+    pub fn new(up: u8, down: u8, idle_ms: u64, pass_while_held: &[u8], debug: bool) -> Self {
+        Self {
+            up,
+            down,
+            idle: Duration::from_millis(idle_ms),
+            pass_while_held: pass_while_held.to_vec(),
+            passing: HashSet::new(),
+            passed: HashSet::new(),
+            held: None,
+            pulse_down: false,
+            release_at: None,
+            debug,
+        }
+    }
+
+    fn release(&mut self, at: Instant, out: &mut Vec<ButtonEvent>) {
+        if let Some(id) = self.held.take() {
+            if self.debug {
+                println!("[turntable] release button {}", id);
+            }
+            out.push(ButtonEvent {
+                id,
+                pressed: false,
+                at,
+            });
+        }
+        self.pulse_down = false;
+        self.release_at = None;
+    }
+
+    fn pulse(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        // An idle timeout that ran out before this pulse still ends the previous scratch
+        self.poll(event.at, out);
+
+        if !event.pressed {
+            if self.passed.remove(&event.id) {
+                out.push(event);
+            } else if self.held == Some(event.id) {
+                self.pulse_down = false;
+                self.release_at = Some(event.at + self.idle);
+            }
+            return;
+        }
+
+        if !self.passing.is_empty() {
+            // End a scratch held from before, then pass the pulse on by itself
+            self.release(event.at, out);
+            self.passed.insert(event.id);
+            out.push(event);
+            return;
+        }
+
+        if self.held != Some(event.id) {
+            // Direction reversal: let go of the other direction first
+            self.release(event.at, out);
+            if self.debug {
+                println!("[turntable] hold button {}", event.id);
+            }
+            self.held = Some(event.id);
+            out.push(event);
+        }
+        self.pulse_down = true;
+        self.release_at = None;
+    }
+}
+
+impl EventFilter for Turntable {
+    fn on_event(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        if event.id == self.up || event.id == self.down {
+            self.pulse(event, out);
+            return;
+        }
+        if self.pass_while_held.contains(&event.id) {
+            if event.pressed {
+                self.passing.insert(event.id);
+            } else {
+                self.passing.remove(&event.id);
+            }
+        }
+        out.push(event);
+    }
+
+    fn poll(&mut self, now: Instant, out: &mut Vec<ButtonEvent>) {
+        if let Some(at) = self.release_at.filter(|at| *at <= now) {
+            if !self.pulse_down {
+                self.release(at, out);
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.release_at
+    }
+
+    fn reset(&mut self) {
+        self.passing.clear();
+        self.passed.clear();
+        self.held = None;
+        self.pulse_down = false;
+        self.release_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: u8, pressed: bool, start: Instant, ms: u64) -> ButtonEvent {
+        ButtonEvent {
+            id,
+            pressed,
+            at: start + Duration::from_millis(ms),
+        }
+    }
+
+    fn edges(events: &[ButtonEvent]) -> Vec<(u8, bool)> {
+        events.iter().map(|e| (e.id, e.pressed)).collect()
+    }
+
+    #[test]
+    fn pulse_train_becomes_one_hold() {
+        let mut tt = Turntable::new(4, 6, 50, &[], false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        for ms in [0, 20, 40, 60] {
+            tt.on_event(event(4, true, start, ms), &mut out);
+            tt.on_event(event(4, false, start, ms + 5), &mut out);
+            tt.poll(start + Duration::from_millis(ms + 10), &mut out);
+        }
+        assert_eq!(edges(&out), vec![(4, true)]);
+        assert_eq!(tt.next_deadline(), Some(start + Duration::from_millis(115)));
+
+        tt.poll(start + Duration::from_millis(115), &mut out);
+        assert_eq!(edges(&out), vec![(4, true), (4, false)]);
+        assert_eq!(out[1].at, start + Duration::from_millis(115));
+    }
+
+    #[test]
+    fn reversal_switches_direction_immediately() {
+        let mut tt = Turntable::new(4, 6, 50, &[], false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        tt.on_event(event(4, true, start, 0), &mut out);
+        tt.on_event(event(4, false, start, 5), &mut out);
+        tt.on_event(event(6, true, start, 10), &mut out);
+        assert_eq!(edges(&out), vec![(4, true), (4, false), (6, true)]);
+        assert!(out[1..]
+            .iter()
+            .all(|e| e.at == start + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn pulses_pass_through_while_bypass_button_is_held() {
+        let mut tt = Turntable::new(4, 6, 50, &[5], false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        // A scratch held from before ends at the first pulse passed through
+        tt.on_event(event(4, true, start, 0), &mut out);
+        tt.on_event(event(5, true, start, 10), &mut out);
+        for ms in [20, 40] {
+            tt.on_event(event(4, true, start, ms), &mut out);
+            tt.on_event(event(4, false, start, ms + 5), &mut out);
+        }
+        tt.on_event(event(5, false, start, 60), &mut out);
+        tt.on_event(event(6, true, start, 70), &mut out);
+        assert_eq!(
+            edges(&out),
+            vec![
+                (4, true),
+                (5, true),
+                (4, false),
+                (4, true),
+                (4, false),
+                (4, true),
+                (4, false),
+                (5, false),
+                (6, true)
+            ]
+        );
+        assert_eq!(tt.next_deadline(), None);
+    }
+
+    #[test]
+    fn held_pulse_does_not_time_out() {
+        let mut tt = Turntable::new(4, 6, 50, &[], false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        tt.on_event(event(6, true, start, 0), &mut out);
+        tt.poll(start + Duration::from_millis(500), &mut out);
+        tt.on_event(event(1, true, start, 500), &mut out);
+        assert_eq!(edges(&out), vec![(6, true), (1, true)]);
+        assert_eq!(tt.next_deadline(), None);
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+/// A keyboard key as written in a mapping file, resolved once at load time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum KeyCode {
+    /// F1-F24
+    Function(u8),
+    Shift,
+    RightShift,
+    Control,
+    RightControl,
+    Alt,
+    RightAlt,
+    Escape,
+    Return,
+    Tab,
+    Space,
+    Backspace,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+    CapsLock,
+    /// Numpad0-Numpad9
+    Numpad(u8),
+    /// Single character key (letters are stored lowercase)
+    Char(char),
+}
+
+/// Named keys and their accepted aliases (matched case-insensitively).
+const NAMED_KEYS: &[(&str, KeyCode)] = &[
+    ("SHIFT", KeyCode::Shift),
+    ("LEFTSHIFT", KeyCode::Shift),
+    ("RIGHTSHIFT", KeyCode::RightShift),
+    ("CONTROL", KeyCode::Control),
+    ("LEFTCONTROL", KeyCode::Control),
+    ("CTRL", KeyCode::Control),
+    ("RIGHTCONTROL", KeyCode::RightControl),
+    ("RIGHTCTRL", KeyCode::RightControl),
+    ("ALT", KeyCode::Alt),
+    ("LEFTALT", KeyCode::Alt),
+    ("RIGHTALT", KeyCode::RightAlt),
+    ("ESCAPE", KeyCode::Escape),
+    ("ESC", KeyCode::Escape),
+    ("RETURN", KeyCode::Return),
+    ("ENTER", KeyCode::Return),
+    ("TAB", KeyCode::Tab),
+    ("SPACE", KeyCode::Space),
+    ("BACKSPACE", KeyCode::Backspace),
+    ("DELETE", KeyCode::Delete),
+    ("HOME", KeyCode::Home),
+    ("END", KeyCode::End),
+    ("PAGEUP", KeyCode::PageUp),
+    ("PAGEDOWN", KeyCode::PageDown),
+    ("UP", KeyCode::Up),
+    ("UPARROW", KeyCode::Up),
+    ("DOWN", KeyCode::Down),
+    ("DOWNARROW", KeyCode::Down),
+    ("LEFT", KeyCode::Left),
+    ("LEFTARROW", KeyCode::Left),
+    ("RIGHT", KeyCode::Right),
+    ("RIGHTARROW", KeyCode::Right),
+    ("CAPSLOCK", KeyCode::CapsLock),
+];
+
+impl KeyCode {
+    /// Parse a key name from a mapping file.
+    /// Unknown names produce an error with a "did you mean" suggestion when one is close enough.
+    pub fn parse(name: &str) -> Result<KeyCode, String> {
+        let upper = name.trim().to_uppercase();
+
+        if let Some(n) = upper.strip_prefix('F').and_then(|n| n.parse::<u8>().ok()) {
+            if (1..=24).contains(&n) {
+                return Ok(KeyCode::Function(n));
+            }
+        }
+
+        if let Some(n) = upper.strip_prefix("NUMPAD").and_then(|n| n.parse::<u8>().ok()) {
+            if n <= 9 {
+                return Ok(KeyCode::Numpad(n));
+            }
+        }
+
+        if let Some((_, key)) = NAMED_KEYS.iter().find(|(alias, _)| *alias == upper) {
+            return Ok(*key);
+        }
+
+        let mut chars = upper.chars();
+        if let (Some(ch), None) = (chars.next(), chars.next()) {
+            return Ok(KeyCode::Char(ch.to_ascii_lowercase()));
+        }
+
+        match suggest(&upper) {
+            Some(s) => Err(format!(
+                "Unknown key \"{}\" (did you mean \"{}\"?)",
+                name, s
+            )),
+            None => Err(format!("Unknown key \"{}\"", name)),
+        }
+    }
+}
+
+impl TryFrom<String> for KeyCode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        KeyCode::parse(&value)
+    }
+}
+
+impl fmt::Display for KeyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyCode::Function(n) => write!(f, "F{}", n),
+            KeyCode::Numpad(n) => write!(f, "Numpad{}", n),
+            KeyCode::Char(ch) => write!(f, "{}", ch.to_ascii_uppercase()),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+/// Find the closest known key name to `upper` (already uppercased).
+fn suggest(upper: &str) -> Option<String> {
+    let function_names: Vec<String> = (1..=24)
+        .map(|n| format!("F{}", n))
+        .chain((0..=9).map(|n| format!("NUMPAD{}", n)))
+        .collect();
+    let candidates = NAMED_KEYS
+        .iter()
+        .map(|(alias, key)| (alias.to_string(), key.to_string()))
+        .chain(function_names.iter().map(|n| {
+            let canonical = KeyCode::parse(n).map(|k| k.to_string()).unwrap_or_default();
+            (n.clone(), canonical)
+        }));
+
+    let max_distance = (upper.len() / 3).max(2);
+    candidates
+        .map(|(alias, canonical)| (edit_distance(upper, &alias), canonical))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, canonical)| canonical)
+}
+
+/// Levenshtein distance, counting an adjacent transposition as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut best = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_case_insensitively() {
+        assert_eq!(KeyCode::parse("f13"), Ok(KeyCode::Function(13)));
+        assert_eq!(KeyCode::parse("RightShift"), Ok(KeyCode::RightShift));
+        assert_eq!(KeyCode::parse("ctrl"), Ok(KeyCode::Control));
+        assert_eq!(KeyCode::parse("D"), Ok(KeyCode::Char('d')));
+        assert_eq!(KeyCode::parse("numpad7"), Ok(KeyCode::Numpad(7)));
+    }
+
+    #[test]
+    fn rejects_out_of_range_function_keys() {
+        assert!(KeyCode::parse("F25").is_err());
+        assert!(KeyCode::parse("F0").is_err());
+    }
+
+    #[test]
+    fn unknown_key_suggests_closest_name() {
+        let err = KeyCode::parse("RightShfit").unwrap_err();
+        assert_eq!(
+            err,
+            "Unknown key \"RightShfit\" (did you mean \"RightShift\"?)"
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for name in ["F21", "RightControl", "Q", "PageDown", "Numpad3"] {
+            let key = KeyCode::parse(name).unwrap();
+            assert_eq!(key.to_string(), name);
+            assert_eq!(KeyCode::parse(&key.to_string()), Ok(key));
+        }
+    }
+}
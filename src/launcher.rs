@@ -191,7 +191,7 @@ pub fn run_launcher(defaults: LauncherDefaults) -> Result<LauncherResult, String
     };
 
     let maps = default_maps();
-    let mode_names: Vec<&str> = maps.keys().copied().collect();
+    let mode_names: Vec<&str> = maps.keys().map(String::as_str).collect();
     println!(
         "Available modes: {} (or \"custom\")",
         mode_names.join(", ")
@@ -1,9 +1,18 @@
 mod cli;
 mod env;
+mod filters;
+mod keys;
 mod launcher;
 mod mapping;
 mod outputs;
+mod pipeline;
+mod profiles;
+mod reload;
+mod rules;
+mod scheduler;
 mod serial;
+mod shutdown;
+mod timer;
 mod types;
 
 fn main() {
@@ -1,36 +1,237 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use serde_json::Value;
 
+use crate::keys::KeyCode;
+use crate::rules;
 use crate::types::{
-    KeyboardButtonEntry, KeyboardMapping, KeyboardSpecialConfig, MappingConfig, OutputType,
-    X360ButtonEntry, X360Mapping,
+    ButtonEntry, Command, DeviceOffsets, FilterConfig, Gestures, KeyboardSpecialConfig, Layer,
+    Macro, MacroStep, MacroTrigger, MappingConfig, Mirror, OutputTarget, OutputType, RuleConfig,
+    Turbo, TurboMode, X360ButtonEntry,
 };
 
-const EMBEDDED_IIDX: &str = include_str!("../mapping/iidx.keyboard.json");
-const EMBEDDED_POPN: &str = include_str!("../mapping/popn.keyboard.json");
-const EMBEDDED_X360: &str = include_str!("../mapping/x360.pad.json");
+/// How long a macro tap holds its output (and pauses after it) unless `tapMs` is set.
+const DEFAULT_MACRO_TAP_MS: u64 = 20;
+
+/// How long a button must be held for a `longPress` macro unless `holdMs` is set.
+const DEFAULT_LONG_PRESS_MS: u64 = 500;
+
+/// How long a button must be held for its `hold` action unless `holdMs` is set.
+const DEFAULT_GESTURE_HOLD_MS: u64 = 400;
+
+/// How soon after a tap a second press counts as a double tap unless `doubleTapMs` is set.
+const DEFAULT_DOUBLE_TAP_MS: u64 = 250;
+
+/// Autofire presses per second unless `rate` is set.
+const DEFAULT_TURBO_RATE: f64 = 10.0;
+
+/// Percentage of each autofire cycle the output is down unless `duty` is set.
+const DEFAULT_TURBO_DUTY: f64 = 50.0;
+
+/// Shortest down or up phase of an autofire cycle.
+const MIN_TURBO_PHASE: Duration = Duration::from_millis(1);
+
+// Every `mapping/*.json` at build time, as `EMBEDDED_MAPPINGS: &[(file name, contents)]`
+include!(concat!(env!("OUT_DIR"), "/embedded_mappings.rs"));
+
+/// Where the built-in mappings live, relative to the working directory.
+const MAPPING_DIR: &str = "mapping";
 
 fn embedded_mapping(file_path: &str) -> Option<&'static str> {
-    match file_path {
-        "mapping/iidx.keyboard.json" => Some(EMBEDDED_IIDX),
-        "mapping/popn.keyboard.json" => Some(EMBEDDED_POPN),
-        "mapping/x360.pad.json" => Some(EMBEDDED_X360),
-        _ => None,
-    }
+    let file_name = file_path.strip_prefix(MAPPING_DIR)?.strip_prefix('/')?;
+    EMBEDDED_MAPPINGS
+        .iter()
+        .find(|(name, _)| *name == file_name)
+        .map(|(_, text)| *text)
+}
+
+/// The per-user mapping directory: `%APPDATA%\ps2iidx_controller\mapping` on Windows,
+/// `$XDG_CONFIG_HOME/ps2iidx_controller/mapping` (or `~/.config/...`) elsewhere.
+pub fn user_mapping_dir() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+    let config = if cfg!(target_os = "windows") {
+        PathBuf::from(var("APPDATA")?)
+    } else {
+        var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| Path::new(&home).join(".config")))?
+    };
+    Some(config.join("ps2iidx_controller").join("mapping"))
+}
+
+/// `*.json` files in `dir` and their contents, ignoring anything unreadable.
+fn json_files(dir: &Path) -> Vec<(PathBuf, String)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<(PathBuf, String)> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| fs::read_to_string(&path).ok().map(|text| (path, text)))
+        .collect();
+    files.sort();
+    files
 }
 
-pub fn default_maps() -> HashMap<&'static str, &'static str> {
-    let mut maps = HashMap::new();
-    maps.insert("iidx", "mapping/iidx.keyboard.json");
-    maps.insert("popn", "mapping/popn.keyboard.json");
-    maps.insert("x360", "mapping/x360.pad.json");
+/// Every mapping mode and the file it loads: the embedded mappings, `mapping/` and the user
+/// mapping directory, keyed by each file's `name` (or its file name without `.json`).
+/// On a clash, `mapping/` beats the embedded copy and the user directory beats both.
+pub fn default_maps() -> BTreeMap<String, String> {
+    // Built-in files keep their `mapping/...` path, so loading falls back to the embedded copy
+    let mut builtin: BTreeMap<String, String> = EMBEDDED_MAPPINGS
+        .iter()
+        .map(|(name, text)| (format!("{}/{}", MAPPING_DIR, name), text.to_string()))
+        .collect();
+    for (path, text) in json_files(Path::new(MAPPING_DIR)) {
+        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            builtin.insert(format!("{}/{}", MAPPING_DIR, name), text);
+        }
+    }
+    let user = user_mapping_dir()
+        .map(|dir| json_files(&dir))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(path, text)| (path.to_string_lossy().into_owned(), text));
+
+    let mut maps = BTreeMap::new();
+    for (path, text) in builtin.into_iter().chain(user) {
+        let name = serde_json::from_str::<Value>(&text)
+            .ok()
+            .and_then(|v| v.get("name")?.as_str().map(String::from))
+            .unwrap_or_else(|| {
+                let file_name = Path::new(&path).file_name().unwrap_or_default();
+                file_name
+                    .to_string_lossy()
+                    .trim_end_matches(".json")
+                    .to_string()
+            });
+        maps.insert(name, path);
+    }
     maps
 }
 
-pub fn load_mapping(file_path: &str) -> Result<MappingConfig, String> {
+/// A button entry set from the command line or environment: `<button>=<entry>`, where the
+/// entry is a key name or entry JSON.
+pub struct Binding {
+    pub spec: String,
+    button: String,
+    entry: Value,
+}
+
+impl Binding {
+    pub fn parse(spec: &str) -> Result<Binding, String> {
+        let invalid = |e: String| format!("Invalid binding \"{}\": {}", spec, e);
+        let (button, text) = spec
+            .split_once('=')
+            .ok_or_else(|| invalid("expected <button>=<entry>".to_string()))?;
+        let (button, text) = (button.trim(), text.trim());
+        button
+            .parse::<u8>()
+            .map_err(|_| invalid(format!("\"{}\" is not a button number", button)))?;
+        let entry = if text.starts_with(['{', '[']) {
+            serde_json::from_str(text).map_err(|e| invalid(e.to_string()))?
+        } else {
+            Value::String(text.to_string())
+        };
+        // Checked on its own first so errors name the binding rather than the mapping file
+        parse_entry(&entry).map_err(invalid)?;
+        Ok(Binding {
+            spec: format!("{}={}", button, text),
+            button: button.to_string(),
+            entry,
+        })
+    }
+}
+
+/// Load a mapping with everything it `extends` and `bindings` on top, and the files it was
+/// read from (the mapping and its bases), for watching them for changes.
+pub fn load_watched_mapping(
+    file_path: &str,
+    bindings: &[Binding],
+) -> Result<(MappingConfig, Vec<PathBuf>), String> {
+    let mut files = Vec::new();
+    let (merged, source_label) = resolve_extended(file_path, bindings, &mut files)?;
+    Ok((parse_mapping_value(&merged, &source_label)?, files))
+}
+
+/// Load mapping JSON with everything it `extends` merged in and `bindings` on top, and where
+/// it came from.
+pub fn resolve_mapping(file_path: &str, bindings: &[Binding]) -> Result<(Value, String), String> {
+    resolve_extended(file_path, bindings, &mut Vec::new())
+}
+
+fn resolve_extended(
+    file_path: &str,
+    bindings: &[Binding],
+    files: &mut Vec<PathBuf>,
+) -> Result<(Value, String), String> {
+    let (mut merged, source_label) = load_extended(file_path, &mut Vec::new(), files)?;
+    if let Some(Value::Object(buttons)) = merged.get_mut("buttons") {
+        for binding in bindings {
+            buttons.insert(binding.button.clone(), binding.entry.clone());
+        }
+    }
+    Ok((merged, source_label))
+}
+
+/// Load `file_path` and its bases; `chain` holds the mappings already being extended and
+/// `files` collects the paths read.
+fn load_extended(
+    file_path: &str,
+    chain: &mut Vec<String>,
+    files: &mut Vec<PathBuf>,
+) -> Result<(Value, String), String> {
+    let (raw_text, source_label, resolved) = read_mapping(file_path)?;
+    // Watched even when the embedded copy was used, so creating the file takes effect
+    files.push(resolved.clone());
+    if chain.contains(&source_label) {
+        return Err(format!(
+            "Mapping extends itself: {} -> {}",
+            chain.join(" -> "),
+            source_label
+        ));
+    }
+
+    let parsed: Value = serde_json::from_str(&raw_text)
+        .map_err(|e| format!("Failed to parse mapping JSON at {}: {}", source_label, e))?;
+    let Value::Object(mut obj) = parsed else {
+        return Err(format!("Mapping JSON must be an object ({})", source_label));
+    };
+    let Some(base) = obj.remove("extends") else {
+        return Ok((Value::Object(obj), source_label));
+    };
+
+    let base = base.as_str().ok_or_else(|| {
+        format!(
+            "\"extends\" must be a mode name or a path ({})",
+            source_label
+        )
+    })?;
+    // A mode name, or a path relative to the extending file
+    let base_path = match default_maps().remove(base) {
+        Some(path) => path,
+        None => {
+            let dir = resolved.parent().unwrap_or(Path::new(""));
+            dir.join(base).to_string_lossy().into_owned()
+        }
+    };
+    chain.push(source_label.clone());
+    let (merged, _) = load_extended(&base_path, chain, files)?;
+    chain.pop();
+
+    let Value::Object(mut merged) = merged else {
+        unreachable!("load_extended returns objects");
+    };
+    merge_mapping(&mut merged, obj);
+    Ok((Value::Object(merged), source_label))
+}
+
+/// Read a mapping file, falling back to the embedded presets. Returns its text, a label for
+/// error messages and its resolved path.
+fn read_mapping(file_path: &str) -> Result<(String, String, PathBuf), String> {
     let resolved = if Path::new(file_path).is_absolute() {
         PathBuf::from(file_path)
     } else {
@@ -51,15 +252,49 @@ pub fn load_mapping(file_path: &str) -> Result<MappingConfig, String> {
             .to_string(),
     };
 
-    let source_label = if resolved.exists() {
-        resolved.display().to_string()
-    } else {
-        format!("{} (embedded)", file_path)
+    let source_label = match resolved.canonicalize() {
+        Ok(path) => path.display().to_string(),
+        Err(_) => format!("{} (embedded)", file_path),
     };
 
-    let parsed: Value = serde_json::from_str(&raw_text)
-        .map_err(|e| format!("Failed to parse mapping JSON at {}: {}", source_label, e))?;
+    Ok((raw_text, source_label, resolved))
+}
 
+/// Fields merged key by key when a mapping extends another; other fields replace the base's.
+const MERGED_FIELDS: [&str; 2] = ["buttons", "special"];
+
+/// Overlay an extending mapping on its base. `null` removes a field, or a button or `special`
+/// setting, from the base.
+fn merge_mapping(
+    base: &mut serde_json::Map<String, Value>,
+    overlay: serde_json::Map<String, Value>,
+) {
+    for (field, value) in overlay {
+        if value.is_null() {
+            base.remove(&field);
+            continue;
+        }
+        match (base.get_mut(&field), value) {
+            (Some(Value::Object(base_obj)), Value::Object(obj))
+                if MERGED_FIELDS.contains(&field.as_str()) =>
+            {
+                for (key, value) in obj {
+                    if value.is_null() {
+                        base_obj.remove(&key);
+                    } else {
+                        base_obj.insert(key, value);
+                    }
+                }
+            }
+            (_, value) => {
+                base.insert(field, value);
+            }
+        }
+    }
+}
+
+/// Parse merged mapping JSON; `source_label` names where it came from in error messages.
+pub fn parse_mapping_value(parsed: &Value, source_label: &str) -> Result<MappingConfig, String> {
     let obj = parsed
         .as_object()
         .ok_or_else(|| format!("Mapping JSON must be an object ({})", source_label))?;
@@ -79,92 +314,498 @@ pub fn load_mapping(file_path: &str) -> Result<MappingConfig, String> {
 
     let name = obj.get("name").and_then(|v| v.as_str()).map(String::from);
 
-    match output {
-        "keyboard" => {
-            let mut buttons = HashMap::new();
-            for (key_id, value) in buttons_obj {
-                let entry = normalize_keyboard_entry(value, key_id)?;
-                buttons.insert(key_id.clone(), entry);
-            }
+    let filters = parse_filters(obj).map_err(|e| format!("{} ({})", e, source_label))?;
+    let mut rules = parse_rules(obj).map_err(|e| format!("{} ({})", e, source_label))?;
+
+    let output = match output {
+        "keyboard" => OutputType::Keyboard,
+        "x360" => OutputType::X360,
+        other => {
+            return Err(format!(
+                "Mapping output must be \"keyboard\" or \"x360\", got \"{}\" ({})",
+                other, source_label
+            ))
+        }
+    };
 
-            let special = if let Some(special_val) = obj.get("special") {
-                Some(parse_keyboard_special(special_val)?)
-            } else {
-                None
-            };
+    let mut buttons = HashMap::new();
+    for (key_id, value) in buttons_obj {
+        let entry =
+            parse_button_entry(value, key_id).map_err(|e| format!("{} ({})", e, source_label))?;
+        buttons.insert(key_id.clone(), entry);
+    }
 
-            Ok(MappingConfig::Keyboard(KeyboardMapping {
-                name,
-                output: OutputType::Keyboard,
-                buttons,
-                special,
-            }))
-        }
-        "x360" => {
-            let mut buttons = HashMap::new();
-            for (key_id, value) in buttons_obj {
-                let entry: X360ButtonEntry = serde_json::from_value(value.clone())
-                    .map_err(|e| format!("Invalid x360 mapping entry for button {}: {}", key_id, e))?;
-                buttons.insert(key_id.clone(), entry);
+    let layers = parse_layers(obj).map_err(|e| format!("{} ({})", e, source_label))?;
+    let offsets = parse_offsets(obj).map_err(|e| format!("{} ({})", e, source_label))?;
+    let side = match obj.get("side") {
+        None => None,
+        Some(value) => Some(
+            serde_json::from_value(value.clone())
+                .map_err(|e| format!("Invalid \"side\": {} ({})", e, source_label))?,
+        ),
+    };
+    let mirror = parse_mirror(obj).map_err(|e| format!("{} ({})", e, source_label))?;
+
+    let layer_names: Vec<&str> = layers.iter().filter_map(|l| l.name.as_deref()).collect();
+    let layer_entries = layers.iter().flat_map(|layer| &layer.buttons);
+    for (key_id, entry) in buttons.iter().chain(layer_entries) {
+        check_toggled_layers(entry, &layer_names).map_err(|e| {
+            format!(
+                "Invalid mapping entry for button {}: {} ({})",
+                key_id, e, source_label
+            )
+        })?;
+    }
+
+    // The legacy `special` block runs after any explicit rules
+    if let Some(special_val) = obj.get("special") {
+        let special =
+            parse_keyboard_special(special_val).map_err(|e| format!("{} ({})", e, source_label))?;
+        rules.extend(rules::from_special(&special, &buttons));
+    }
+
+    Ok(MappingConfig {
+        name,
+        output,
+        buttons,
+        filters,
+        rules,
+        layers,
+        offsets,
+        side,
+        mirror,
+    })
+}
+
+/// `"mirror": { "keys": [<key 1>, ..., <key 7>], "turntable": [<up>, <down>] }`
+fn parse_mirror(obj: &serde_json::Map<String, Value>) -> Result<Option<Mirror>, String> {
+    let Some(value) = obj.get("mirror") else {
+        return Ok(None);
+    };
+    let mirror: Mirror =
+        serde_json::from_value(value.clone()).map_err(|e| format!("Invalid \"mirror\": {}", e))?;
+    let turntable = mirror.turntable.iter().flat_map(|&(up, down)| [up, down]);
+    let mut seen = Vec::new();
+    for id in mirror.keys.iter().copied().chain(turntable) {
+        if seen.contains(&id) {
+            return Err(format!("Invalid \"mirror\": button {} is listed twice", id));
+        }
+        seen.push(id);
+    }
+    Ok(Some(mirror))
+}
+
+/// `"offsets": { "keyboard": <ms>, "x360": <ms> }`
+fn parse_offsets(obj: &serde_json::Map<String, Value>) -> Result<DeviceOffsets, String> {
+    match obj.get("offsets") {
+        None => Ok(DeviceOffsets::default()),
+        Some(value) => {
+            serde_json::from_value(value.clone()).map_err(|e| format!("Invalid \"offsets\": {}", e))
+        }
+    }
+}
+
+fn parse_filters(obj: &serde_json::Map<String, Value>) -> Result<Vec<FilterConfig>, String> {
+    let Some(value) = obj.get("filters") else {
+        return Ok(Vec::new());
+    };
+    let arr = value.as_array().ok_or("\"filters\" must be an array")?;
+    arr.iter()
+        .enumerate()
+        .map(|(index, item)| {
+            serde_json::from_value(item.clone())
+                .map_err(|e| e.to_string())
+                .and_then(|config| check_filter(&config).map(|_| config))
+                .map_err(|e| format!("Invalid filter {}: {}", index, e))
+        })
+        .collect()
+}
+
+fn check_filter(config: &FilterConfig) -> Result<(), String> {
+    if let FilterConfig::Socd { pairs, .. } = config {
+        let mut seen = Vec::new();
+        for &(a, b) in pairs {
+            if a == b {
+                return Err(format!("button {} can't oppose itself", a));
+            }
+            if let Some(id) = [a, b].into_iter().find(|id| seen.contains(id)) {
+                return Err(format!("button {} is in more than one pair", id));
             }
+            seen.extend([a, b]);
+        }
+    }
+    Ok(())
+}
 
-            Ok(MappingConfig::X360(X360Mapping {
+/// `"rules"` is either a list of rules or the name of a built-in rule set.
+fn parse_rules(obj: &serde_json::Map<String, Value>) -> Result<Vec<RuleConfig>, String> {
+    match obj.get("rules") {
+        None => Ok(Vec::new()),
+        Some(Value::String(name)) => rules::preset(name).ok_or_else(|| {
+            format!(
+                "Unknown rule preset \"{}\". Available: {}",
                 name,
-                output: OutputType::X360,
-                buttons,
-            }))
-        }
-        other => Err(format!(
-            "Mapping output must be \"keyboard\" or \"x360\", got \"{}\" ({})",
-            other,
-            source_label
-        )),
+                rules::preset_names().join(", ")
+            )
+        }),
+        Some(Value::Array(arr)) => arr
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                serde_json::from_value(item.clone())
+                    .map_err(|e| format!("Invalid rule {}: {}", index, e))
+            })
+            .collect(),
+        Some(_) => Err("\"rules\" must be an array or a preset name".to_string()),
     }
 }
 
-fn normalize_keyboard_entry(value: &Value, key_id: &str) -> Result<KeyboardButtonEntry, String> {
-    if let Some(s) = value.as_str() {
-        return Ok(KeyboardButtonEntry { key: s.to_string() });
+/// Parse `"layers"`, resolving each layer's button entries like the base `"buttons"`.
+fn parse_layers(obj: &serde_json::Map<String, Value>) -> Result<Vec<Layer<ButtonEntry>>, String> {
+    let Some(value) = obj.get("layers") else {
+        return Ok(Vec::new());
+    };
+    let arr = value.as_array().ok_or("\"layers\" must be an array")?;
+
+    let mut layers: Vec<Layer<ButtonEntry>> = Vec::new();
+    for (index, item) in arr.iter().enumerate() {
+        let raw: Layer<Value> = serde_json::from_value(item.clone())
+            .map_err(|e| format!("Invalid layer {}: {}", index, e))?;
+        if layers.iter().any(|l| l.button == raw.button) {
+            return Err(format!(
+                "Invalid layer {}: button {} already switches another layer",
+                index, raw.button
+            ));
+        }
+        let mut buttons = HashMap::new();
+        for (key_id, value) in &raw.buttons {
+            let entry = parse_button_entry(value, key_id)
+                .map_err(|e| format!("Invalid layer {}: {}", index, e))?;
+            buttons.insert(key_id.clone(), entry);
+        }
+        layers.push(Layer {
+            name: raw.name,
+            button: raw.button,
+            mode: raw.mode,
+            fallthrough: raw.fallthrough,
+            buttons,
+        });
     }
+    Ok(layers)
+}
+
+/// Parse a button entry: a key name, `{ "key": ... }`, `{ "keys": [...] }`, a pad entry
+/// (`{ "type": ... }`, which may also carry `"key"`/`"keys"`), or an array of these
+/// for outputs pressed together.
+fn parse_button_entry(value: &Value, key_id: &str) -> Result<ButtonEntry, String> {
+    parse_entry(value).map_err(|e| format!("Invalid mapping entry for button {}: {}", key_id, e))
+}
+
+fn parse_entry(value: &Value) -> Result<ButtonEntry, String> {
     if let Some(obj) = value.as_object() {
-        if let Some(key) = obj.get("key").and_then(|v| v.as_str()) {
-            return Ok(KeyboardButtonEntry {
-                key: key.to_string(),
+        if GESTURE_FIELDS.iter().any(|field| obj.contains_key(*field)) {
+            return Ok(ButtonEntry {
+                gestures: Some(Box::new(parse_gestures(obj)?)),
+                ..Default::default()
             });
         }
     }
-    Err(format!(
-        "Invalid keyboard mapping entry for button {}",
-        key_id
-    ))
+
+    let mut entry = ButtonEntry::default();
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .try_for_each(|item| parse_outputs(item, &mut entry))?,
+        other => parse_outputs(other, &mut entry)?,
+    }
+    if entry.outputs.is_empty() && entry.macros.is_empty() && entry.commands.is_empty() {
+        return Err("no outputs".to_string());
+    }
+    if entry.latch && entry.turbo.is_some() {
+        return Err(
+            "\"latch\" can't be combined with \"turbo\" (use turbo mode \"toggle\")".to_string(),
+        );
+    }
+    Ok(entry)
+}
+
+/// Fields that make an entry a set of tap / hold / double-tap actions.
+const GESTURE_FIELDS: [&str; 3] = ["tap", "hold", "doubleTap"];
+
+/// Timing fields allowed next to the gesture fields.
+const GESTURE_TIMES: [&str; 2] = ["holdMs", "doubleTapMs"];
+
+/// Parse `{ "tap": <entry>, "hold": <entry>, "doubleTap": <entry>, "holdMs", "doubleTapMs" }`.
+fn parse_gestures(obj: &serde_json::Map<String, Value>) -> Result<Gestures, String> {
+    if let Some(field) = obj.keys().find(|field| {
+        !GESTURE_FIELDS.contains(&field.as_str()) && !GESTURE_TIMES.contains(&field.as_str())
+    }) {
+        return Err(format!(
+            "\"{}\" can't be combined with \"tap\", \"hold\" or \"doubleTap\"",
+            field
+        ));
+    }
+    let action = |field: &str| -> Result<Option<ButtonEntry>, String> {
+        let Some(value) = obj.get(field) else {
+            return Ok(None);
+        };
+        let entry = parse_entry(value).map_err(|e| format!("\"{}\": {}", field, e))?;
+        if entry.gestures.is_some() {
+            return Err(format!("\"{}\" can't have gestures of its own", field));
+        }
+        Ok(Some(entry))
+    };
+
+    Ok(Gestures {
+        tap: action("tap")?,
+        hold: action("hold")?,
+        double_tap: action("doubleTap")?,
+        hold_ms: parse_ms(obj, "holdMs", DEFAULT_GESTURE_HOLD_MS)?,
+        double_tap_ms: parse_ms(obj, "doubleTapMs", DEFAULT_DOUBLE_TAP_MS)?,
+    })
+}
+
+fn parse_ms(
+    obj: &serde_json::Map<String, Value>,
+    field: &str,
+    default: u64,
+) -> Result<u64, String> {
+    match obj.get(field) {
+        None => Ok(default),
+        Some(value) => value
+            .as_u64()
+            .ok_or_else(|| format!("\"{}\" must be a number of milliseconds", field)),
+    }
 }
 
-fn parse_keyboard_special(value: &Value) -> Result<KeyboardSpecialConfig, String> {
+/// Check that every `toggleLayer` command names a layer of the mapping.
+fn check_toggled_layers(entry: &ButtonEntry, names: &[&str]) -> Result<(), String> {
+    for command in &entry.commands {
+        if let Command::ToggleLayer { layer } = command {
+            if !names.contains(&layer.as_str()) {
+                return Err(format!("unknown layer \"{}\"", layer));
+            }
+        }
+    }
+    entry
+        .gesture_entries()
+        .try_for_each(|e| check_toggled_layers(e, names))
+}
+
+fn parse_key(value: &Value) -> Result<OutputTarget, String> {
+    let name = value.as_str().ok_or("keys must be key names")?;
+    KeyCode::parse(name).map(OutputTarget::Key)
+}
+
+fn parse_pad(obj: &serde_json::Map<String, Value>) -> Result<OutputTarget, String> {
+    let pad: X360ButtonEntry =
+        serde_json::from_value(Value::Object(obj.clone())).map_err(|e| e.to_string())?;
+    Ok(OutputTarget::Pad(pad))
+}
+
+fn parse_outputs(value: &Value, entry: &mut ButtonEntry) -> Result<(), String> {
+    let obj = match value {
+        Value::String(_) => {
+            entry.outputs.push(parse_key(value)?);
+            return Ok(());
+        }
+        Value::Object(obj) => obj,
+        _ => return Err("expected a key name, an object or an array".to_string()),
+    };
+
+    if let Some(offset) = obj.get("offsetMs") {
+        if entry.offset_ms != 0 {
+            return Err("only one \"offsetMs\" per entry".to_string());
+        }
+        entry.offset_ms = offset
+            .as_i64()
+            .ok_or("\"offsetMs\" must be a number of milliseconds")?;
+    }
+    if obj.contains_key("macro") {
+        entry.macros.push(parse_macro(obj)?);
+        return Ok(());
+    }
+    if obj.contains_key("command") {
+        let command =
+            serde_json::from_value(Value::Object(obj.clone())).map_err(|e| e.to_string())?;
+        entry.commands.push(command);
+        return Ok(());
+    }
+
+    if let Some(turbo) = obj.get("turbo") {
+        if entry.turbo.is_some() {
+            return Err("only one \"turbo\" per entry".to_string());
+        }
+        entry.turbo = Some(parse_turbo(turbo)?);
+    }
+    if let Some(latch) = obj.get("latch") {
+        entry.latch |= latch.as_bool().ok_or("\"latch\" must be true or false")?;
+    }
+
+    let outputs = &mut entry.outputs;
+    let start = outputs.len();
+    if let Some(name) = obj.get("key") {
+        outputs.push(parse_key(name)?);
+    }
+    if let Some(keys) = obj.get("keys") {
+        let keys = keys
+            .as_array()
+            .ok_or("\"keys\" must be an array of key names")?;
+        for name in keys {
+            outputs.push(parse_key(name)?);
+        }
+    }
+    if obj.contains_key("type") {
+        let mut pad = obj.clone();
+        pad.remove("key");
+        pad.remove("keys");
+        pad.remove("turbo");
+        pad.remove("latch");
+        pad.remove("offsetMs");
+        outputs.push(parse_pad(&pad)?);
+    }
+    if outputs.len() == start {
+        return Err("expected \"key\", \"keys\", a pad \"type\" or a \"macro\"".to_string());
+    }
+    Ok(())
+}
+
+/// Parse `"turbo": { "rate": <Hz>, "duty": <percent>, "mode": "hold" | "toggle" }`.
+fn parse_turbo(value: &Value) -> Result<Turbo, String> {
+    let obj = value.as_object().ok_or("\"turbo\" must be an object")?;
+    let number = |field: &str, default: f64| -> Result<f64, String> {
+        match obj.get(field) {
+            None => Ok(default),
+            Some(value) => value
+                .as_f64()
+                .filter(|n| *n > 0.0)
+                .ok_or_else(|| format!("turbo \"{}\" must be a positive number", field)),
+        }
+    };
+
+    let mode = match obj.get("mode").map(|v| v.as_str()) {
+        None | Some(Some("hold")) => TurboMode::Hold,
+        Some(Some("toggle")) => TurboMode::Toggle,
+        Some(_) => return Err("turbo \"mode\" must be \"hold\" or \"toggle\"".to_string()),
+    };
+    let rate = number("rate", DEFAULT_TURBO_RATE)?;
+    let duty = number("duty", DEFAULT_TURBO_DUTY)?;
+    if duty >= 100.0 {
+        return Err("turbo \"duty\" must be below 100 (percent)".to_string());
+    }
+
+    let period = Duration::from_secs_f64(1.0 / rate);
+    let down = period.mul_f64(duty / 100.0);
+    // Both halves of a cycle must be long enough for the device to see them
+    if down < MIN_TURBO_PHASE || period - down < MIN_TURBO_PHASE {
+        return Err(format!(
+            "turbo rate {} Hz is too fast for a {}% duty cycle",
+            rate, duty
+        ));
+    }
+    Ok(Turbo { mode, period, down })
+}
+
+/// Parse `{ "macro": [steps], "on": ..., "holdMs": ..., "tapMs": ... }`.
+fn parse_macro(obj: &serde_json::Map<String, Value>) -> Result<Macro, String> {
+    let ms = |field: &str, default: u64| parse_ms(obj, field, default);
+
+    let trigger = match obj.get("on").map(|v| v.as_str()) {
+        None | Some(Some("press")) => MacroTrigger::Press,
+        Some(Some("release")) => MacroTrigger::Release,
+        Some(Some("longPress")) => MacroTrigger::LongPress(ms("holdMs", DEFAULT_LONG_PRESS_MS)?),
+        Some(_) => {
+            return Err("macro \"on\" must be \"press\", \"release\" or \"longPress\"".to_string())
+        }
+    };
+
+    let steps = obj
+        .get("macro")
+        .and_then(|v| v.as_array())
+        .ok_or("\"macro\" must be an array of steps")?
+        .iter()
+        .enumerate()
+        .map(|(index, step)| {
+            parse_macro_step(step).map_err(|e| format!("macro step {}: {}", index, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Macro {
+        trigger,
+        tap_ms: ms("tapMs", DEFAULT_MACRO_TAP_MS)?,
+        steps,
+    })
+}
+
+fn parse_macro_step(value: &Value) -> Result<MacroStep, String> {
     let obj = value
         .as_object()
-        .ok_or("\"special\" must be an object")?;
+        .filter(|obj| obj.len() == 1)
+        .ok_or("expected one of \"down\", \"up\", \"tap\", \"text\" or \"delay\"")?;
+    let (kind, arg) = obj.iter().next().expect("one field");
 
-    let ignore_key = obj.get("ignoreKey").and_then(|v| v.as_str()).map(String::from);
+    let target = |arg: &Value| match arg {
+        Value::Object(pad) => parse_pad(pad),
+        other => parse_key(other),
+    };
+    match kind.as_str() {
+        "down" => target(arg).map(MacroStep::Down),
+        "up" => target(arg).map(MacroStep::Up),
+        "tap" => target(arg).map(MacroStep::Tap),
+        "text" => arg
+            .as_str()
+            .map(|text| MacroStep::Text(text.to_string()))
+            .ok_or_else(|| "\"text\" must be a string".to_string()),
+        "delay" => arg
+            .as_u64()
+            .map(MacroStep::Delay)
+            .ok_or_else(|| "\"delay\" must be a number of milliseconds".to_string()),
+        other => Err(format!("unknown step \"{}\"", other)),
+    }
+}
 
-    let tap_keys = obj.get("tapKeys").and_then(|v| {
-        v.as_array().map(|arr| {
-            arr.iter()
-                .filter_map(|item| item.as_str().map(String::from))
-                .collect()
+fn parse_key_list(
+    obj: &serde_json::Map<String, Value>,
+    field: &str,
+) -> Result<Option<Vec<KeyCode>>, String> {
+    let Some(value) = obj.get(field) else {
+        return Ok(None);
+    };
+    let arr = value
+        .as_array()
+        .ok_or_else(|| format!("\"special.{}\" must be an array of key names", field))?;
+    arr.iter()
+        .map(|item| {
+            let name = item
+                .as_str()
+                .ok_or_else(|| format!("\"special.{}\" must be an array of key names", field))?;
+            KeyCode::parse(name).map_err(|e| format!("Invalid \"special.{}\": {}", field, e))
         })
-    });
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
 
-    let tap_duration_ms = obj
-        .get("tapDurationMs")
-        .and_then(|v| v.as_u64());
+fn parse_keyboard_special(value: &Value) -> Result<KeyboardSpecialConfig, String> {
+    let obj = value.as_object().ok_or("\"special\" must be an object")?;
 
-    let release_on_ignore = obj.get("releaseOnIgnore").and_then(|v| {
-        v.as_array().map(|arr| {
-            arr.iter()
-                .filter_map(|item| item.as_str().map(String::from))
-                .collect()
-        })
-    });
+    let ignore_key = match obj.get("ignoreKey") {
+        Some(v) => {
+            let name = v
+                .as_str()
+                .ok_or("\"special.ignoreKey\" must be a key name")?;
+            Some(
+                KeyCode::parse(name)
+                    .map_err(|e| format!("Invalid \"special.ignoreKey\": {}", e))?,
+            )
+        }
+        None => None,
+    };
+
+    let tap_keys = parse_key_list(obj, "tapKeys")?;
+
+    let tap_duration_ms = obj.get("tapDurationMs").and_then(|v| v.as_u64());
+
+    let release_on_ignore = parse_key_list(obj, "releaseOnIgnore")?;
 
     Ok(KeyboardSpecialConfig {
         ignore_key,
@@ -173,3 +814,482 @@ fn parse_keyboard_special(value: &Value) -> Result<KeyboardSpecialConfig, String
         release_on_ignore,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::FilterChain;
+    use crate::rules::RuleEngine;
+    use crate::types::{ButtonEvent, RuleAction, Side, SocdMode};
+    use std::time::{Duration, Instant};
+
+    fn load_mapping(file_path: &str, bindings: &[Binding]) -> Result<MappingConfig, String> {
+        load_watched_mapping(file_path, bindings).map(|(mapping, _)| mapping)
+    }
+
+    fn parse_mapping(raw_text: &str, source_label: &str) -> Result<MappingConfig, String> {
+        let parsed: Value = serde_json::from_str(raw_text).unwrap();
+        parse_mapping_value(&parsed, source_label)
+    }
+
+    #[test]
+    fn parses_filters_in_order() {
+        let mapping = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": { "1": "A" },
+                "filters": [
+                    { "type": "remap", "buttons": { "1": 2 } },
+                    { "type": "turntable", "up": 4, "down": 6, "idleMs": 50 },
+                    { "type": "socd", "mode": "neutral", "pairs": [[4, 6]] }
+                ]
+            }"#,
+            "test",
+        )
+        .unwrap();
+        match mapping.filters() {
+            [FilterConfig::Remap { buttons }, FilterConfig::Turntable {
+                up, down, idle_ms, ..
+            }, _] => {
+                assert_eq!(buttons.get(&1), Some(&2));
+                assert_eq!((*up, *down, *idle_ms), (4, 6, Some(50)));
+            }
+            other => panic!("unexpected filters {:?}", other),
+        }
+        match &mapping.filters()[2] {
+            FilterConfig::Socd { mode, pairs } => {
+                assert_eq!(*mode, SocdMode::Neutral);
+                assert_eq!(pairs, &[(4, 6)]);
+            }
+            other => panic!("unexpected filter {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_button_in_two_socd_pairs() {
+        let err = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": {},
+                "filters": [{ "type": "socd", "pairs": [[4, 6], [6, 8]] }]
+            }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Invalid filter 0: button 6 is in more than one pair (test)"
+        );
+    }
+
+    #[test]
+    fn legacy_special_becomes_rules_after_explicit_ones() {
+        let mapping = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": { "4": "F13", "5": "F14" },
+                "rules": [{ "while": [1], "buttons": [2], "do": "suppress" }],
+                "special": { "ignoreKey": "F14", "tapKeys": ["F13"] }
+            }"#,
+            "test",
+        )
+        .unwrap();
+        let actions: Vec<&RuleAction> = mapping.rules().iter().map(|r| &r.action).collect();
+        assert_eq!(
+            actions,
+            vec![&RuleAction::Suppress, &RuleAction::Tap { ms: Some(13) }]
+        );
+        assert_eq!(mapping.rules()[1].while_held, vec![5]);
+    }
+
+    #[test]
+    fn iidx2p_preset_mirrors_keys_and_turntable() {
+        let mut mapping = load_mapping("mapping/iidx2p.keyboard.json", &[]).unwrap();
+        assert_eq!(mapping.side, Some(Side::P2));
+        mapping.play_on(Side::P2).unwrap();
+        let FilterConfig::Remap { buttons } = &mapping.filters()[0] else {
+            panic!("unexpected filters {:?}", mapping.filters());
+        };
+        assert_eq!(buttons.get(&15), Some(&7));
+        assert_eq!(buttons.get(&7), Some(&15));
+        assert_eq!(buttons.get(&10), Some(&8));
+        assert_eq!((buttons.get(&4), buttons.get(&6)), (Some(&6), Some(&4)));
+        assert_eq!(buttons.get(&11), None);
+
+        let mut unmirrored = load_mapping("mapping/popn.keyboard.json", &[]).unwrap();
+        assert!(unmirrored.play_on(Side::P2).is_err());
+
+        let err = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": {},
+                "mirror": { "keys": [1, 2], "turntable": [2, 3] }
+            }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert_eq!(err, "Invalid \"mirror\": button 2 is listed twice (test)");
+    }
+
+    #[test]
+    fn discovers_every_builtin_mapping_by_name() {
+        let maps = default_maps();
+        for (name, file) in [
+            ("iidx", "iidx.keyboard.json"),
+            ("iidx2p", "iidx2p.keyboard.json"),
+            ("popn", "popn.keyboard.json"),
+            ("x360", "x360.pad.json"),
+        ] {
+            let path = format!("mapping/{}", file);
+            assert_eq!(maps.get(name), Some(&path));
+            assert!(
+                embedded_mapping(&path).is_some(),
+                "{} is not embedded",
+                file
+            );
+        }
+    }
+
+    #[test]
+    fn popn_preset_keeps_its_own_special() {
+        let mapping = load_mapping("mapping/popn.keyboard.json", &[]).unwrap();
+        let key = |id: &str| mapping.buttons[id].keys().next().unwrap();
+        assert_eq!(key("4"), KeyCode::Char('d'));
+        assert_eq!(key("5"), KeyCode::Function(14));
+        assert_eq!(mapping.name(), Some("popn"));
+        assert!(mapping.filters().is_empty());
+        assert!(mapping.mirror.is_none());
+        // Rules from its `special`, which tap F13/F15; button 4 is D here
+        assert!(!mapping.rules().is_empty());
+        assert!(mapping
+            .rules()
+            .iter()
+            .all(|rule| !rule.buttons.contains(&4)));
+    }
+
+    #[test]
+    fn iidx_preset_ships_legacy_effector_rules() {
+        let mapping = load_mapping("mapping/iidx.keyboard.json", &[]).unwrap();
+        assert_eq!(mapping.rules(), rules::preset("iidx-legacy").unwrap());
+    }
+
+    #[test]
+    fn iidx_preset_taps_every_pulse_while_effector_is_held() {
+        let mapping = load_mapping("mapping/iidx.keyboard.json", &[]).unwrap();
+        let mut chain = FilterChain::from_config(mapping.filters(), false);
+        chain.push(Box::new(RuleEngine::new(mapping.rules().to_vec(), false)));
+        let start = Instant::now();
+        let event = |id, pressed, ms| ButtonEvent {
+            id,
+            pressed,
+            at: start + Duration::from_millis(ms),
+        };
+        let mut out = Vec::new();
+
+        chain.on_event(event(5, true, 0), &mut out);
+        for ms in [10, 30, 50, 70] {
+            chain.on_event(event(4, true, ms), &mut out);
+            chain.on_event(event(4, false, ms + 5), &mut out);
+        }
+        chain.poll(start + Duration::from_millis(500), &mut out);
+        let taps = out.iter().filter(|e| e.id == 4 && e.pressed).count();
+        let releases = out.iter().filter(|e| e.id == 4 && !e.pressed).count();
+        assert_eq!((taps, releases), (4, 4));
+    }
+
+    #[test]
+    fn extends_paths_merge_buttons_and_detect_cycles() {
+        let dir = std::env::temp_dir().join(format!("mapping-extends-{}", std::process::id()));
+        fs::create_dir_all(dir.join("base")).unwrap();
+        let write = |name: &str, text: &str| fs::write(dir.join(name), text).unwrap();
+        write(
+            "base/kb.json",
+            r#"{ "output": "keyboard", "buttons": { "1": "A", "2": "B", "3": "C" } }"#,
+        );
+        write(
+            "child.json",
+            r#"{ "extends": "base/kb.json", "buttons": { "2": "X", "3": null } }"#,
+        );
+        write(
+            "loop-a.json",
+            r#"{ "extends": "loop-b.json", "buttons": {} }"#,
+        );
+        write(
+            "loop-b.json",
+            r#"{ "extends": "loop-a.json", "buttons": {} }"#,
+        );
+
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let (merged, _) = resolve_mapping(&path("child.json"), &[]).unwrap();
+        assert_eq!(merged["buttons"], serde_json::json!({ "1": "A", "2": "X" }));
+        assert_eq!(merged["output"], "keyboard");
+
+        let err = load_mapping(&path("loop-a.json"), &[]).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(err.starts_with("Mapping extends itself: "), "{}", err);
+        assert!(err.ends_with("loop-a.json"), "{}", err);
+    }
+
+    #[test]
+    fn bindings_override_entries_and_are_validated() {
+        let bindings = [
+            Binding::parse("4=F13").unwrap(),
+            Binding::parse(r#"12 = { "keys": ["Shift", "Q"] }"#).unwrap(),
+        ];
+        assert_eq!(bindings[1].spec, r#"12={ "keys": ["Shift", "Q"] }"#);
+        let mapping = load_mapping("mapping/popn.keyboard.json", &bindings).unwrap();
+        let keys = |id: &str| mapping.buttons[id].keys().collect::<Vec<_>>();
+        assert_eq!(keys("4"), vec![KeyCode::Function(13)]);
+        assert_eq!(keys("12"), vec![KeyCode::Shift, KeyCode::Char('q')]);
+
+        let err = |spec: &str| Binding::parse(spec).err().unwrap();
+        assert_eq!(err("4"), "Invalid binding \"4\": expected <button>=<entry>");
+        assert_eq!(
+            err("x=A"),
+            "Invalid binding \"x=A\": \"x\" is not a button number"
+        );
+        assert!(err("4=F99").starts_with("Invalid binding \"4=F99\": "));
+    }
+
+    #[test]
+    fn rejects_unknown_rule_preset() {
+        let err = parse_mapping(
+            r#"{ "output": "x360", "buttons": {}, "rules": "iidx-modern" }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Unknown rule preset \"iidx-modern\". Available: iidx-legacy (test)"
+        );
+    }
+
+    #[test]
+    fn parses_layer_entries_like_base_buttons() {
+        let mapping = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": { "0": "F21" },
+                "layers": [{ "button": 0, "mode": "toggle", "buttons": { "8": "Numpad1" } }]
+            }"#,
+            "test",
+        )
+        .unwrap();
+        let layer = &mapping.layers[0];
+        assert_eq!(layer.mode, crate::types::LayerMode::Toggle);
+        assert!(layer.fallthrough);
+        assert_eq!(
+            layer.buttons["8"].outputs,
+            vec![OutputTarget::Key(KeyCode::Numpad(1))]
+        );
+    }
+
+    #[test]
+    fn parses_chords_and_outputs_on_both_devices() {
+        let mapping = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": {
+                    "1": ["Control", "F1"],
+                    "2": { "keys": ["Shift", "F2"] },
+                    "3": { "type": "button", "name": "START", "key": "F3" }
+                }
+            }"#,
+            "test",
+        )
+        .unwrap();
+        let keys = |id: &str| mapping.buttons[id].keys().collect::<Vec<_>>();
+        assert_eq!(keys("1"), vec![KeyCode::Control, KeyCode::Function(1)]);
+        assert_eq!(keys("2"), vec![KeyCode::Shift, KeyCode::Function(2)]);
+        assert_eq!(keys("3"), vec![KeyCode::Function(3)]);
+        assert!(mapping.buttons["3"].uses_pad());
+        assert!(mapping.uses_keyboard() && mapping.uses_pad());
+    }
+
+    #[test]
+    fn parses_macro_entries() {
+        let mapping = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": {
+                    "7": ["F1", {
+                        "macro": [
+                            { "tap": "Down" },
+                            { "delay": 100 },
+                            { "down": { "type": "button", "name": "A" } },
+                            { "text": "abc" }
+                        ],
+                        "on": "longPress",
+                        "holdMs": 800
+                    }]
+                }
+            }"#,
+            "test",
+        )
+        .unwrap();
+        let entry = &mapping.buttons["7"];
+        assert_eq!(entry.keys().collect::<Vec<_>>(), vec![KeyCode::Function(1)]);
+        assert_eq!(entry.macros[0].trigger, MacroTrigger::LongPress(800));
+        assert_eq!(entry.macros[0].tap_ms, DEFAULT_MACRO_TAP_MS);
+        assert_eq!(entry.macros[0].steps[3], MacroStep::Text("abc".to_string()));
+        assert!(mapping.uses_keyboard() && mapping.uses_pad());
+
+        let err = parse_mapping(
+            r#"{ "output": "keyboard", "buttons": { "7": { "macro": [{ "wait": 5 }] } } }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Invalid mapping entry for button 7: macro step 0: unknown step \"wait\" (test)"
+        );
+    }
+
+    #[test]
+    fn parses_turbo_rate_and_duty() {
+        let mapping = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": { "1": { "key": "Down", "turbo": { "rate": 20, "duty": 25 } } }
+            }"#,
+            "test",
+        )
+        .unwrap();
+        let turbo = mapping.buttons["1"].turbo.unwrap();
+        assert_eq!(turbo.mode, TurboMode::Hold);
+        assert_eq!(turbo.period, Duration::from_millis(50));
+        assert_eq!(turbo.down, Duration::from_micros(12500));
+
+        let too_fast = r#"{ "key": "Down", "turbo": { "rate": 1000 } }"#;
+        let err = parse_mapping(
+            &format!(
+                r#"{{ "output": "keyboard", "buttons": {{ "1": {} }} }}"#,
+                too_fast
+            ),
+            "test",
+        )
+        .unwrap_err();
+        assert!(err.contains("too fast"), "{}", err);
+    }
+
+    #[test]
+    fn parses_entry_and_device_offsets() {
+        let mapping = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "offsets": { "x360": 4 },
+                "buttons": {
+                    "8": [{ "key": "Up", "offsetMs": -6 }, { "type": "button", "name": "A" }],
+                    "9": "Down"
+                }
+            }"#,
+            "test",
+        )
+        .unwrap();
+        assert_eq!(mapping.buttons["8"].offset_ms, -6);
+        assert_eq!(mapping.offsets.keyboard, 0);
+        assert_eq!(mapping.offsets.x360, 4);
+        assert_eq!(mapping.min_offset_ms(), -6);
+
+        let err = parse_mapping(
+            r#"{ "output": "keyboard", "offsets": { "mouse": 1 }, "buttons": {} }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("Invalid \"offsets\": unknown field"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parses_gesture_actions_and_commands() {
+        let mapping = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": {
+                    "13": {
+                        "tap": "Escape",
+                        "hold": { "command": "toggleLayer", "layer": "numpad" },
+                        "doubleTap": { "macro": [{ "text": "gg" }] },
+                        "holdMs": 600
+                    }
+                },
+                "layers": [{ "name": "numpad", "button": 0, "buttons": {} }]
+            }"#,
+            "test",
+        )
+        .unwrap();
+        let gestures = mapping.buttons["13"].gestures.as_ref().unwrap();
+        assert_eq!(gestures.hold_ms, 600);
+        assert_eq!(gestures.double_tap_ms, DEFAULT_DOUBLE_TAP_MS);
+        assert_eq!(
+            gestures.hold.as_ref().unwrap().commands,
+            vec![Command::ToggleLayer {
+                layer: "numpad".to_string()
+            }]
+        );
+        assert!(mapping.uses_keyboard());
+
+        let err = parse_mapping(
+            r#"{
+                "output": "keyboard",
+                "buttons": { "13": { "hold": { "command": "toggleLayer", "layer": "menu" } } }
+            }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Invalid mapping entry for button 13: unknown layer \"menu\" (test)"
+        );
+    }
+
+    #[test]
+    fn rejects_entry_without_outputs() {
+        let err = parse_mapping(
+            r#"{ "output": "keyboard", "buttons": { "4": { "name": "A" } } }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            concat!(
+                "Invalid mapping entry for button 4: ",
+                "expected \"key\", \"keys\", a pad \"type\" or a \"macro\" (test)"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_two_layers_on_one_button() {
+        let err = parse_mapping(
+            r#"{ "output": "x360", "buttons": {}, "layers": [
+                { "button": 0, "buttons": {} },
+                { "button": 0, "buttons": {} }
+            ] }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Invalid layer 1: button 0 already switches another layer (test)"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_filter_type() {
+        let err = parse_mapping(
+            r#"{ "output": "x360", "buttons": {}, "filters": [{ "type": "wobble" }] }"#,
+            "test",
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("Invalid filter 0: unknown variant `wobble`"),
+            "{}",
+            err
+        );
+        assert!(err.ends_with("(test)"), "{}", err);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::layers::Resolved;
+use crate::types::{ButtonEntry, Gestures};
+
+/// How long a tap action's outputs stay down; the button itself was already let go.
+const GESTURE_TAP_MS: u64 = 30;
+
+enum State {
+    /// Held, not yet long enough to count as a hold
+    Pressed { config: Gestures, since: Instant },
+    /// A hold or double-tap action, held until the button is released
+    Active(ButtonEntry),
+    /// Released after a short press: a tap, unless pressed again in time for a double tap
+    Released { config: Gestures, at: Instant },
+}
+
+impl State {
+    fn deadline(&self) -> Option<Instant> {
+        match self {
+            State::Pressed { config, since } => config
+                .hold
+                .as_ref()
+                .map(|_| *since + Duration::from_millis(config.hold_ms)),
+            State::Active(_) => None,
+            State::Released { config, at } => {
+                Some(*at + Duration::from_millis(config.double_tap_ms))
+            }
+        }
+    }
+}
+
+/// Tells taps, holds and double taps of buttons with gesture entries apart, and decides
+/// when each of their actions is pressed and released.
+pub struct GestureTracker {
+    states: HashMap<u8, State>,
+    /// Tap actions waiting to be released: (deadline, button, action)
+    tap_releases: Vec<(Instant, u8, ButtonEntry)>,
+    debug: bool,
+}
+
+impl GestureTracker {
+    pub fn new(debug: bool) -> Self {
+        Self {
+            states: HashMap::new(),
+            tap_releases: Vec::new(),
+            debug,
+        }
+    }
+
+    pub fn press(
+        &mut self,
+        button: u8,
+        config: &Gestures,
+        at: Instant,
+        out: &mut Vec<(Instant, u8, Resolved<ButtonEntry>)>,
+    ) {
+        // Only a pending double tap is left here; `poll` settles expired ones first
+        if let Some(State::Released { config: first, .. }) = self.states.remove(&button) {
+            if let Some(action) = first.double_tap {
+                self.log(button, "double tap");
+                out.push((at, button, Resolved::Press(action.clone())));
+                self.states.insert(button, State::Active(action));
+                return;
+            }
+        }
+        let config = config.clone();
+        self.states
+            .insert(button, State::Pressed { config, since: at });
+    }
+
+    pub fn release(
+        &mut self,
+        button: u8,
+        at: Instant,
+        out: &mut Vec<(Instant, u8, Resolved<ButtonEntry>)>,
+    ) {
+        match self.states.remove(&button) {
+            Some(State::Pressed { config, .. }) if config.double_tap.is_some() => {
+                self.states.insert(button, State::Released { config, at });
+            }
+            Some(State::Pressed { config, .. }) => self.tap(button, config, at, out),
+            Some(State::Active(action)) => out.push((at, button, Resolved::Release(action))),
+            Some(released @ State::Released { .. }) => {
+                self.states.insert(button, released);
+            }
+            None => {}
+        }
+    }
+
+    fn tap(
+        &mut self,
+        button: u8,
+        config: Gestures,
+        at: Instant,
+        out: &mut Vec<(Instant, u8, Resolved<ButtonEntry>)>,
+    ) {
+        let Some(action) = config.tap else {
+            return;
+        };
+        self.log(button, "tap");
+        out.push((at, button, Resolved::Press(action.clone())));
+        let release_at = at + Duration::from_millis(GESTURE_TAP_MS);
+        self.tap_releases.push((release_at, button, action));
+    }
+
+    /// Settle holds, taps and tap releases that are due at `now`, in time order.
+    pub fn poll(&mut self, now: Instant, out: &mut Vec<(Instant, u8, Resolved<ButtonEntry>)>) {
+        let mut due: Vec<(Instant, u8)> = self
+            .states
+            .iter()
+            .filter_map(|(&button, state)| state.deadline().map(|at| (at, button)))
+            .filter(|(at, _)| *at <= now)
+            .collect();
+        due.sort();
+        for (at, button) in due {
+            match self.states.remove(&button) {
+                Some(State::Pressed { config, .. }) => {
+                    let action = config.hold.expect("only holds have a press deadline");
+                    self.log(button, "hold");
+                    out.push((at, button, Resolved::Press(action.clone())));
+                    self.states.insert(button, State::Active(action));
+                }
+                Some(State::Released { config, .. }) => self.tap(button, config, at, out),
+                Some(State::Active(_)) | None => {}
+            }
+        }
+
+        self.tap_releases.sort_by_key(|(at, _, _)| *at);
+        let count = self.tap_releases.partition_point(|(at, _, _)| *at <= now);
+        for (at, button, action) in self.tap_releases.drain(..count) {
+            out.push((at, button, Resolved::Release(action)));
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let states = self.states.values().filter_map(State::deadline);
+        let taps = self.tap_releases.iter().map(|(at, _, _)| *at);
+        states.chain(taps).min()
+    }
+
+    /// Forget every pending gesture (the caller releases held outputs).
+    pub fn reset(&mut self) {
+        self.states.clear();
+        self.tap_releases.clear();
+    }
+
+    fn log(&self, button: u8, gesture: &str) {
+        if self.debug {
+            println!("[gesture] button {}: {}", button, gesture);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyCode;
+    use crate::types::OutputTarget;
+
+    fn action(name: &str) -> Option<ButtonEntry> {
+        Some(ButtonEntry {
+            outputs: vec![OutputTarget::Key(KeyCode::parse(name).unwrap())],
+            ..Default::default()
+        })
+    }
+
+    fn config(double_tap: bool) -> Gestures {
+        Gestures {
+            tap: action("Escape"),
+            hold: action("F1"),
+            double_tap: if double_tap { action("F2") } else { None },
+            hold_ms: 400,
+            double_tap_ms: 250,
+        }
+    }
+
+    /// Run (ms, pressed) events, polling before each one and at `end`.
+    fn run(config: &Gestures, events: &[(u64, bool)], end: u64) -> Vec<(u64, String)> {
+        let mut tracker = GestureTracker::new(false);
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut out = Vec::new();
+        for &(at, pressed) in events {
+            tracker.poll(ms(at), &mut out);
+            if pressed {
+                tracker.press(9, config, ms(at), &mut out);
+            } else {
+                tracker.release(9, ms(at), &mut out);
+            }
+        }
+        tracker.poll(ms(end), &mut out);
+        out.into_iter()
+            .map(|(at, _, resolved)| {
+                let (edge, entry) = match resolved {
+                    Resolved::Press(entry) => ("down", entry),
+                    Resolved::Release(entry) => ("up", entry),
+                };
+                let at = (at - start).as_millis() as u64;
+                (at, format!("{} {}", entry.outputs[0], edge))
+            })
+            .collect()
+    }
+
+    fn expect(edges: &[(u64, &str)]) -> Vec<(u64, String)> {
+        edges.iter().map(|&(at, e)| (at, e.to_string())).collect()
+    }
+
+    #[test]
+    fn short_press_taps_and_long_press_holds() {
+        let taps = run(&config(false), &[(0, true), (100, false)], 1000);
+        assert_eq!(taps, expect(&[(100, "Escape down"), (130, "Escape up")]));
+
+        let holds = run(&config(false), &[(0, true), (700, false)], 1000);
+        assert_eq!(holds, expect(&[(400, "F1 down"), (700, "F1 up")]));
+    }
+
+    #[test]
+    fn double_tap_waits_for_second_press() {
+        let single = run(&config(true), &[(0, true), (100, false)], 1000);
+        assert_eq!(single, expect(&[(350, "Escape down"), (380, "Escape up")]));
+
+        let double = run(
+            &config(true),
+            &[(0, true), (100, false), (200, true), (260, false)],
+            1000,
+        );
+        assert_eq!(double, expect(&[(200, "F2 down"), (260, "F2 up")]));
+    }
+}
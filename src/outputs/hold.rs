@@ -0,0 +1,88 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// Tracks which buttons (or macros) are holding each output (key, pad button, ...).
+/// An output goes down when the first button holds it and up only when the last one lets go,
+/// so buttons mapped to the same output don't release each other.
+pub struct HoldCounter<T, H = u8> {
+    holders: HashMap<T, HashSet<H>>,
+}
+
+impl<T: Eq + Hash + Clone, H: Eq + Hash> HoldCounter<T, H> {
+    pub fn new() -> Self {
+        Self {
+            holders: HashMap::new(),
+        }
+    }
+
+    /// Register `button` as holding `target`.
+    /// Returns true if the output was not held before and should be pressed now.
+    pub fn press(&mut self, target: &T, button: H) -> bool {
+        let holders = self.holders.entry(target.clone()).or_default();
+        let was_idle = holders.is_empty();
+        holders.insert(button);
+        was_idle
+    }
+
+    /// Unregister `button` from `target`.
+    /// Returns true if it was the last holder and the output should be released now.
+    pub fn release(&mut self, target: &T, button: H) -> bool {
+        let Some(holders) = self.holders.get_mut(target) else {
+            return false;
+        };
+        if !holders.remove(&button) || !holders.is_empty() {
+            return false;
+        }
+        self.holders.remove(target);
+        true
+    }
+
+    pub fn is_held(&self, target: &T) -> bool {
+        self.holders.contains_key(target)
+    }
+
+    /// Forget every hold, returning the outputs that need releasing.
+    pub fn take_all(&mut self) -> Vec<T> {
+        self.holders.drain().map(|(target, _)| target).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_output_released_by_last_holder() {
+        let mut holds = HoldCounter::new();
+        assert!(holds.press(&"F18", 3));
+        assert!(!holds.press(&"F18", 9));
+
+        assert!(!holds.release(&"F18", 3));
+        assert!(holds.is_held(&"F18"));
+        assert!(holds.release(&"F18", 9));
+        assert!(!holds.is_held(&"F18"));
+    }
+
+    #[test]
+    fn repeated_press_from_same_button_counts_once() {
+        let mut holds = HoldCounter::new();
+        assert!(holds.press(&"F21", 0));
+        assert!(!holds.press(&"F21", 0));
+        assert!(holds.release(&"F21", 0));
+        assert!(!holds.release(&"F21", 0));
+    }
+
+    #[test]
+    fn take_all_empties_counter() {
+        let mut holds = HoldCounter::new();
+        holds.press(&"F18", 3);
+        holds.press(&"F18", 9);
+        holds.press(&"F21", 0);
+
+        let mut released = holds.take_all();
+        released.sort();
+        assert_eq!(released, vec!["F18", "F21"]);
+        assert!(!holds.is_held(&"F18"));
+        assert!(holds.take_all().is_empty());
+    }
+}
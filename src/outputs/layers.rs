@@ -0,0 +1,254 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use crate::types::{ButtonEvent, Layer, LayerMode};
+
+/// What a button event turns into once layers are taken into account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolved<E> {
+    Press(E),
+    Release(E),
+}
+
+/// Tracks active layers and which entry each held button was pressed as.
+///
+/// A button is released as whatever it was pressed as, and leaving a layer releases
+/// everything still held from it. Layer buttons only switch layers and have no output.
+pub struct Layers<E> {
+    layers: Vec<Layer<E>>,
+    /// Active layer indices, most recently activated last
+    active: Vec<usize>,
+    /// Held buttons: the layer they were resolved in (None for the base) and their entry
+    pressed: HashMap<u8, (Option<usize>, E)>,
+    debug: bool,
+}
+
+impl<E: Clone> Layers<E> {
+    pub fn new(layers: Vec<Layer<E>>, debug: bool) -> Self {
+        Self {
+            layers,
+            active: Vec::new(),
+            pressed: HashMap::new(),
+            debug,
+        }
+    }
+
+    /// Resolve `event` against the active layers and `base`, appending (button, output) pairs.
+    pub fn resolve(
+        &mut self,
+        event: &ButtonEvent,
+        base: &HashMap<String, E>,
+        out: &mut Vec<(u8, Resolved<E>)>,
+    ) {
+        if let Some(index) = self.layers.iter().position(|l| l.button == event.id) {
+            self.switch_layer(index, event.pressed, out);
+            return;
+        }
+
+        if !event.pressed {
+            if let Some((_, entry)) = self.pressed.remove(&event.id) {
+                out.push((event.id, Resolved::Release(entry)));
+            }
+            return;
+        }
+
+        let key = event.id.to_string();
+        let mut resolved = None;
+        for &index in self.active.iter().rev() {
+            let layer = &self.layers[index];
+            if let Some(entry) = layer.buttons.get(&key) {
+                resolved = Some((Some(index), entry.clone()));
+                break;
+            }
+            if !layer.fallthrough {
+                return;
+            }
+        }
+        let Some((layer, entry)) =
+            resolved.or_else(|| base.get(&key).map(|entry| (None, entry.clone())))
+        else {
+            return;
+        };
+        if let Entry::Vacant(slot) = self.pressed.entry(event.id) {
+            slot.insert((layer, entry.clone()));
+            out.push((event.id, Resolved::Press(entry)));
+        }
+    }
+
+    /// Switch the layer called `name` on or off, whatever its button is doing.
+    pub fn toggle(&mut self, name: &str, out: &mut Vec<(u8, Resolved<E>)>) {
+        if let Some(index) = self
+            .layers
+            .iter()
+            .position(|l| l.name.as_deref() == Some(name))
+        {
+            let activate = !self.active.contains(&index);
+            self.set_active(index, activate, out);
+        }
+    }
+
+    fn switch_layer(&mut self, index: usize, pressed: bool, out: &mut Vec<(u8, Resolved<E>)>) {
+        let is_active = self.active.contains(&index);
+        let activate = match self.layers[index].mode {
+            LayerMode::Momentary => pressed,
+            LayerMode::Toggle if pressed => !is_active,
+            LayerMode::Toggle => return,
+        };
+        self.set_active(index, activate, out);
+    }
+
+    fn set_active(&mut self, index: usize, activate: bool, out: &mut Vec<(u8, Resolved<E>)>) {
+        if activate == self.active.contains(&index) {
+            return;
+        }
+
+        if self.debug {
+            let layer = &self.layers[index];
+            let name = layer.name.clone().unwrap_or_else(|| format!("#{}", index));
+            let state = if activate { "on" } else { "off" };
+            println!("[layers] {} {}", name, state);
+        }
+
+        if activate {
+            self.active.push(index);
+            return;
+        }
+        self.active.retain(|&i| i != index);
+
+        // Release everything the layer is still holding
+        let mut held: Vec<u8> = self
+            .pressed
+            .iter()
+            .filter(|(_, (layer, _))| *layer == Some(index))
+            .map(|(&button, _)| button)
+            .collect();
+        held.sort();
+        for button in held {
+            if let Some((_, entry)) = self.pressed.remove(&button) {
+                out.push((button, Resolved::Release(entry)));
+            }
+        }
+    }
+
+    /// Forget all layer and button state (the caller releases held outputs).
+    pub fn reset(&mut self) {
+        self.active.clear();
+        self.pressed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn layer(mode: LayerMode, fallthrough: bool) -> Layer<&'static str> {
+        Layer {
+            name: Some("numpad".to_string()),
+            button: 0,
+            mode,
+            fallthrough,
+            buttons: HashMap::from([("1".to_string(), "Numpad1")]),
+        }
+    }
+
+    fn base() -> HashMap<String, &'static str> {
+        HashMap::from([
+            ("0".to_string(), "F21"),
+            ("1".to_string(), "F13"),
+            ("2".to_string(), "F14"),
+        ])
+    }
+
+    fn run(
+        layers: &mut Layers<&'static str>,
+        events: &[(u8, bool)],
+    ) -> Vec<(u8, Resolved<&'static str>)> {
+        let base = base();
+        let mut out = Vec::new();
+        for &(id, pressed) in events {
+            let event = ButtonEvent {
+                id,
+                pressed,
+                at: Instant::now(),
+            };
+            layers.resolve(&event, &base, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn momentary_layer_overrides_and_falls_through() {
+        let mut layers = Layers::new(vec![layer(LayerMode::Momentary, true)], false);
+        let out = run(
+            &mut layers,
+            &[
+                (0, true),
+                (1, true),
+                (2, true),
+                (1, false),
+                (2, false),
+                (0, false),
+                (1, true),
+            ],
+        );
+        assert_eq!(
+            out,
+            vec![
+                (1, Resolved::Press("Numpad1")),
+                (2, Resolved::Press("F14")),
+                (1, Resolved::Release("Numpad1")),
+                (2, Resolved::Release("F14")),
+                (1, Resolved::Press("F13")),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaving_layer_releases_its_keys_only() {
+        let mut layers = Layers::new(vec![layer(LayerMode::Momentary, true)], false);
+        let out = run(
+            &mut layers,
+            &[
+                (2, true),
+                (0, true),
+                (1, true),
+                (0, false),
+                (1, false),
+                (2, false),
+            ],
+        );
+        assert_eq!(
+            out,
+            vec![
+                (2, Resolved::Press("F14")),
+                (1, Resolved::Press("Numpad1")),
+                (1, Resolved::Release("Numpad1")),
+                (2, Resolved::Release("F14")),
+            ]
+        );
+    }
+
+    #[test]
+    fn toggle_layer_without_fallthrough_blocks_base() {
+        let mut layers = Layers::new(vec![layer(LayerMode::Toggle, false)], false);
+        let out = run(
+            &mut layers,
+            &[
+                (0, true),
+                (0, false),
+                (2, true),
+                (1, true),
+                (0, true),
+                (2, false),
+            ],
+        );
+        assert_eq!(
+            out,
+            vec![
+                (1, Resolved::Press("Numpad1")),
+                (1, Resolved::Release("Numpad1")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::types::{Macro, MacroStep, OutputTarget};
+
+/// One timed operation of a running macro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroOp {
+    Press(OutputTarget),
+    Release(OutputTarget),
+    Text(String),
+}
+
+/// A macro that has started: its remaining operations in time order, and the outputs it
+/// is holding so they can be let go if it is canceled.
+pub struct RunningMacro {
+    pub button: u8,
+    /// Offset of the entry the macro belongs to
+    pub offset_ms: i64,
+    ops: VecDeque<(Instant, MacroOp)>,
+    /// When the last step (including a trailing delay) is over
+    end: Instant,
+    pub held: Vec<OutputTarget>,
+}
+
+impl RunningMacro {
+    /// Lay out the macro's steps on a timeline starting at `start`.
+    pub fn start(button: u8, config: &Macro, offset_ms: i64, start: Instant) -> Self {
+        let tap = Duration::from_millis(config.tap_ms);
+        let mut at = start;
+        let mut ops = VecDeque::new();
+        for step in &config.steps {
+            match step {
+                MacroStep::Down(target) => ops.push_back((at, MacroOp::Press(target.clone()))),
+                MacroStep::Up(target) => ops.push_back((at, MacroOp::Release(target.clone()))),
+                MacroStep::Tap(target) => {
+                    ops.push_back((at, MacroOp::Press(target.clone())));
+                    ops.push_back((at + tap, MacroOp::Release(target.clone())));
+                    at += tap * 2;
+                }
+                MacroStep::Text(text) => ops.push_back((at, MacroOp::Text(text.clone()))),
+                MacroStep::Delay(ms) => at += Duration::from_millis(*ms),
+            }
+        }
+        Self {
+            button,
+            offset_ms,
+            ops,
+            end: at,
+            held: Vec::new(),
+        }
+    }
+
+    /// Move the operations due at `now` to `out`.
+    pub fn take_due(&mut self, now: Instant, out: &mut Vec<(Instant, MacroOp)>) {
+        while let Some((at, _)) = self.ops.front() {
+            if *at > now {
+                break;
+            }
+            out.extend(self.ops.pop_front());
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        Some(self.ops.front().map_or(self.end, |(at, _)| *at))
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        self.ops.is_empty() && self.end <= now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyCode;
+    use crate::types::MacroTrigger;
+
+    #[test]
+    fn steps_are_laid_out_on_a_timeline() {
+        let key = |name| OutputTarget::Key(KeyCode::parse(name).unwrap());
+        let config = Macro {
+            trigger: MacroTrigger::Press,
+            tap_ms: 20,
+            steps: vec![
+                MacroStep::Tap(key("Down")),
+                MacroStep::Delay(100),
+                MacroStep::Down(key("Shift")),
+                MacroStep::Text("a".to_string()),
+                MacroStep::Up(key("Shift")),
+            ],
+        };
+        let start = Instant::now();
+        let mut running = RunningMacro::start(0, &config, 0, start);
+        let ms = |n| start + Duration::from_millis(n);
+
+        let mut due = Vec::new();
+        running.take_due(ms(0), &mut due);
+        assert_eq!(due, vec![(ms(0), MacroOp::Press(key("Down")))]);
+        assert_eq!(running.next_deadline(), Some(ms(20)));
+
+        due.clear();
+        running.take_due(ms(140), &mut due);
+        assert_eq!(
+            due,
+            vec![
+                (ms(20), MacroOp::Release(key("Down"))),
+                (ms(140), MacroOp::Press(key("Shift"))),
+                (ms(140), MacroOp::Text("a".to_string())),
+                (ms(140), MacroOp::Release(key("Shift"))),
+            ]
+        );
+        assert!(running.is_done(ms(140)));
+    }
+}
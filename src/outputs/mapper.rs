@@ -0,0 +1,783 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use super::gestures::GestureTracker;
+use super::hold::HoldCounter;
+use super::layers::{Layers, Resolved};
+use super::macros::{MacroOp, RunningMacro};
+use super::turbo::Autofire;
+use crate::types::{
+    ButtonEntry, ButtonEvent, Command, DeviceOffsets, Macro, MacroTrigger, MappingConfig,
+    OutputAction, OutputAdapter, OutputTarget, TimedAction, TurboMode,
+};
+
+/// Who is holding an output: a button's own entry, or a macro it started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Holder {
+    Button(u8),
+    Macro(u8),
+}
+
+/// A timed step that has come due: a macro operation or an autofire edge.
+enum Due {
+    Macro(usize, MacroOp),
+    Turbo(usize, bool),
+}
+
+/// Mapping logic shared by every output device: resolves buttons (through layers) to their
+/// entries and emits key and pad actions for them.
+pub struct ButtonMapper {
+    buttons: HashMap<String, ButtonEntry>,
+    layers: Layers<ButtonEntry>,
+    gestures: GestureTracker,
+    held: HoldCounter<OutputTarget, Holder>,
+    macros: Vec<RunningMacro>,
+    /// Long-press macros waiting for their hold time: (button, deadline, entry offset, macro)
+    long_presses: Vec<(u8, Instant, i64, Macro)>,
+    /// Buttons whose current press canceled a macro, so it starts nothing else
+    canceling: HashSet<u8>,
+    autofire: Vec<Autofire>,
+    /// Outputs (and their entry offset) held down by latching buttons until their next press
+    latched: HashMap<u8, (Vec<OutputTarget>, i64)>,
+    /// Deadline of the most recent action on each output (`None` for text); later actions on
+    /// the same output never run before it
+    last_deadlines: HashMap<Option<OutputTarget>, Instant>,
+    /// Delay of every action: the global offset, plus whatever makes negative offsets fit
+    offset: Duration,
+    /// Added to device and entry offsets so the most negative one comes out as 0
+    lead_ms: i64,
+    device_offsets: DeviceOffsets,
+    debug: bool,
+}
+
+fn device_label(target: &OutputTarget) -> &'static str {
+    match target {
+        OutputTarget::Key(_) => "keyboard",
+        OutputTarget::Pad(_) => "x360",
+    }
+}
+
+/// The output an action drives, or `None` for text.
+fn action_target(action: &OutputAction) -> Option<OutputTarget> {
+    match action {
+        OutputAction::KeyDown(key) | OutputAction::KeyUp(key) => Some(OutputTarget::Key(*key)),
+        OutputAction::PadDown(entry) | OutputAction::PadUp(entry) => {
+            Some(OutputTarget::Pad(entry.clone()))
+        }
+        OutputAction::Text(_) => None,
+    }
+}
+
+fn press_action(target: OutputTarget) -> OutputAction {
+    match target {
+        OutputTarget::Key(key) => OutputAction::KeyDown(key),
+        OutputTarget::Pad(entry) => OutputAction::PadDown(entry),
+    }
+}
+
+fn release_action(target: OutputTarget) -> OutputAction {
+    match target {
+        OutputTarget::Key(key) => OutputAction::KeyUp(key),
+        OutputTarget::Pad(entry) => OutputAction::PadUp(entry),
+    }
+}
+
+impl ButtonMapper {
+    pub fn new(mapping: &MappingConfig, offset_ms: u64, debug: bool) -> Self {
+        let lead_ms = -mapping.min_offset_ms();
+        if debug && lead_ms > 0 {
+            println!(
+                "[offset] negative offsets: everything else is delayed by {} ms",
+                lead_ms
+            );
+        }
+        Self {
+            buttons: mapping.buttons.clone(),
+            layers: Layers::new(mapping.layers.clone(), debug),
+            gestures: GestureTracker::new(debug),
+            held: HoldCounter::new(),
+            macros: Vec::new(),
+            long_presses: Vec::new(),
+            canceling: HashSet::new(),
+            autofire: Vec::new(),
+            latched: HashMap::new(),
+            last_deadlines: HashMap::new(),
+            offset: Duration::from_millis(offset_ms),
+            lead_ms,
+            device_offsets: mapping.offsets,
+            debug,
+        }
+    }
+
+    /// Emit an action for an event at `at`, delayed by the offsets of its device and of the
+    /// entry behind it (`offset_ms`).
+    fn emit(
+        &mut self,
+        at: Instant,
+        offset_ms: i64,
+        action: OutputAction,
+        out: &mut Vec<TimedAction>,
+    ) {
+        // Text is typed on the keyboard
+        let device_ms = action_target(&action)
+            .map_or(self.device_offsets.keyboard, |t| self.device_offsets.of(&t));
+        let extra_ms = (self.lead_ms + device_ms + offset_ms).max(0) as u64;
+        let at = at + self.offset + Duration::from_millis(extra_ms);
+        self.emit_at(at, action, out);
+    }
+
+    /// Emit an action at `at`, keeping actions on each output in the order their events
+    /// arrived.
+    fn emit_at(&mut self, at: Instant, action: OutputAction, out: &mut Vec<TimedAction>) {
+        let last = self
+            .last_deadlines
+            .entry(action_target(&action))
+            .or_insert(at);
+        let at = at.max(*last);
+        *last = at;
+        out.push(TimedAction { at, action });
+    }
+
+    /// Press an entry's outputs in order.
+    /// Only the first hold of a shared output reaches the device.
+    fn press(
+        &mut self,
+        outputs: &[OutputTarget],
+        button: u8,
+        offset_ms: i64,
+        at: Instant,
+        out: &mut Vec<TimedAction>,
+    ) {
+        for target in outputs.iter().cloned() {
+            if self.debug {
+                println!(
+                    "[{}] press {} (id {})",
+                    device_label(&target),
+                    target,
+                    button
+                );
+            }
+            if self.held.press(&target, Holder::Button(button)) {
+                self.emit(at, offset_ms, press_action(target), out);
+            }
+        }
+    }
+
+    /// Release an entry's outputs in reverse order.
+    /// Only the last release of a shared output reaches the device.
+    fn release(
+        &mut self,
+        outputs: &[OutputTarget],
+        button: u8,
+        offset_ms: i64,
+        at: Instant,
+        out: &mut Vec<TimedAction>,
+    ) {
+        for target in outputs.iter().rev().cloned() {
+            if self.debug {
+                println!(
+                    "[{}] release {} (id {})",
+                    device_label(&target),
+                    target,
+                    button
+                );
+            }
+            if self.held.release(&target, Holder::Button(button)) {
+                self.emit(at, offset_ms, release_action(target), out);
+            } else if self.debug && self.held.is_held(&target) {
+                println!(
+                    "[{}] {} still held by another button",
+                    device_label(&target),
+                    target
+                );
+            }
+        }
+    }
+
+    fn start_macros(&mut self, entry: &ButtonEntry, button: u8, pressed: bool, at: Instant) {
+        for config in &entry.macros {
+            match (config.trigger, pressed) {
+                (MacroTrigger::Press, true) | (MacroTrigger::Release, false) => {
+                    if self.debug {
+                        println!("[macro] button {} started", button);
+                    }
+                    let running = RunningMacro::start(button, config, entry.offset_ms, at);
+                    self.macros.push(running);
+                }
+                (MacroTrigger::LongPress(ms), true) => {
+                    let deadline = at + Duration::from_millis(ms);
+                    let pending = (button, deadline, entry.offset_ms, config.clone());
+                    self.long_presses.push(pending);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Stop every macro `button` started, letting go of what they hold.
+    /// Returns whether anything was canceled.
+    fn cancel_macros(&mut self, button: u8, at: Instant, out: &mut Vec<TimedAction>) -> bool {
+        let pending = self.long_presses.len();
+        self.long_presses.retain(|(b, ..)| *b != button);
+        let mut canceled = self.long_presses.len() != pending;
+
+        while let Some(index) = self.macros.iter().position(|m| m.button == button) {
+            let running = self.macros.remove(index);
+            self.release_macro_holds(running, at, out);
+            canceled = true;
+        }
+        if canceled && self.debug {
+            println!("[macro] button {} canceled", button);
+        }
+        canceled
+    }
+
+    fn release_macro_holds(
+        &mut self,
+        running: RunningMacro,
+        at: Instant,
+        out: &mut Vec<TimedAction>,
+    ) {
+        let holder = Holder::Macro(running.button);
+        for target in running.held.into_iter().rev() {
+            if self.held.release(&target, holder) {
+                self.emit(at, running.offset_ms, release_action(target), out);
+            }
+        }
+    }
+
+    /// Press or release a resolved entry at `at` (the time of the event behind it).
+    fn apply(
+        &mut self,
+        button: u8,
+        output: Resolved<ButtonEntry>,
+        at: Instant,
+        out: &mut Vec<TimedAction>,
+    ) {
+        let mut fired = Vec::new();
+        match output {
+            Resolved::Press(entry) => match &entry.gestures {
+                Some(gestures) => self.gestures.press(button, gestures, at, &mut fired),
+                None => self.press_entry(button, entry, at, out),
+            },
+            Resolved::Release(entry) => match entry.gestures {
+                Some(_) => self.gestures.release(button, at, &mut fired),
+                None => self.release_entry(button, entry, at, out),
+            },
+        }
+        for (at, button, output) in fired {
+            self.apply(button, output, at, out);
+        }
+    }
+
+    fn press_entry(
+        &mut self,
+        button: u8,
+        entry: ButtonEntry,
+        event_at: Instant,
+        out: &mut Vec<TimedAction>,
+    ) {
+        for command in &entry.commands {
+            self.run_command(command, event_at, out);
+        }
+
+        // Pressing a button again while its macro runs cancels the macro
+        if self.cancel_macros(button, event_at, out) {
+            self.canceling.insert(button);
+        } else {
+            self.start_macros(&entry, button, true, event_at);
+        }
+        // ...and a press switches toggled autofire off
+        if self.stop_autofire(button, event_at, out).is_some() {
+            return;
+        }
+        if let Some((outputs, offset_ms)) = self.latched.remove(&button) {
+            if self.debug {
+                println!("[latch] button {} unlatched", button);
+            }
+            self.release(&outputs, button, offset_ms, event_at, out);
+            return;
+        }
+        match entry.turbo {
+            Some(turbo) => {
+                if self.debug {
+                    println!("[turbo] button {} started", button);
+                }
+                let autofire =
+                    Autofire::start(button, entry.outputs, entry.offset_ms, turbo, event_at);
+                self.autofire.push(autofire);
+            }
+            None if entry.latch => {
+                if self.debug {
+                    let names: Vec<String> = entry.outputs.iter().map(|t| t.to_string()).collect();
+                    println!("[latch] button {} latched ({})", button, names.join(" + "));
+                }
+                self.press(&entry.outputs, button, entry.offset_ms, event_at, out);
+                self.latched
+                    .insert(button, (entry.outputs, entry.offset_ms));
+            }
+            None => self.press(&entry.outputs, button, entry.offset_ms, event_at, out),
+        }
+    }
+
+    fn release_entry(
+        &mut self,
+        button: u8,
+        entry: ButtonEntry,
+        event_at: Instant,
+        out: &mut Vec<TimedAction>,
+    ) {
+        let hold_turbo = self
+            .autofire
+            .iter()
+            .any(|a| a.button == button && a.mode() == TurboMode::Hold);
+        if hold_turbo {
+            self.stop_autofire(button, event_at, out);
+        } else if entry.turbo.is_none() && !entry.latch {
+            self.release(&entry.outputs, button, entry.offset_ms, event_at, out);
+        }
+        self.long_presses.retain(|(b, ..)| *b != button);
+        if !self.canceling.remove(&button) {
+            self.start_macros(&entry, button, false, event_at);
+        }
+    }
+
+    fn run_command(&mut self, command: &Command, at: Instant, out: &mut Vec<TimedAction>) {
+        if self.debug {
+            println!("[command] {:?}", command);
+        }
+        match command {
+            Command::ReleaseAll => self.release_all(at + self.offset, out),
+            Command::ToggleLayer { layer } => {
+                let mut resolved = Vec::new();
+                self.layers.toggle(layer, &mut resolved);
+                for (button, output) in resolved {
+                    self.apply(button, output, at, out);
+                }
+            }
+        }
+    }
+
+    /// Stop `button`'s autofire, letting go of its outputs if they are down.
+    /// Returns the mode it was running in.
+    fn stop_autofire(
+        &mut self,
+        button: u8,
+        at: Instant,
+        out: &mut Vec<TimedAction>,
+    ) -> Option<TurboMode> {
+        let index = self.autofire.iter().position(|a| a.button == button)?;
+        let autofire = self.autofire.remove(index);
+        if autofire.is_down() {
+            self.release(&autofire.outputs, button, autofire.offset_ms, at, out);
+        }
+        if self.debug {
+            println!("[turbo] button {} stopped", button);
+        }
+        Some(autofire.mode())
+    }
+
+    /// Settle gestures, start long presses and run macro steps and autofire edges that are due
+    /// at `now`.
+    fn advance(&mut self, now: Instant, out: &mut Vec<TimedAction>) {
+        let mut fired = Vec::new();
+        self.gestures.poll(now, &mut fired);
+        for (at, button, output) in fired {
+            self.apply(button, output, at, out);
+        }
+
+        self.long_presses.sort_by_key(|(_, deadline, ..)| *deadline);
+        while self
+            .long_presses
+            .first()
+            .is_some_and(|(_, deadline, ..)| *deadline <= now)
+        {
+            let (button, deadline, offset_ms, config) = self.long_presses.remove(0);
+            if self.debug {
+                println!("[macro] button {} started (long press)", button);
+            }
+            self.macros
+                .push(RunningMacro::start(button, &config, offset_ms, deadline));
+        }
+
+        // Steps of concurrent macros and autofire run in time order
+        let mut due: Vec<(Instant, Due)> = Vec::new();
+        let mut ops = Vec::new();
+        for (index, running) in self.macros.iter_mut().enumerate() {
+            running.take_due(now, &mut ops);
+            due.extend(ops.drain(..).map(|(at, op)| (at, Due::Macro(index, op))));
+        }
+        let mut edges = Vec::new();
+        for (index, autofire) in self.autofire.iter_mut().enumerate() {
+            autofire.take_due(now, &mut edges);
+            due.extend(
+                edges
+                    .drain(..)
+                    .map(|(at, down)| (at, Due::Turbo(index, down))),
+            );
+        }
+        due.sort_by_key(|(at, _)| *at);
+
+        for (at, step) in due {
+            let (index, op) = match step {
+                Due::Macro(index, op) => (index, op),
+                Due::Turbo(index, down) => {
+                    let autofire = &self.autofire[index];
+                    let (button, outputs) = (autofire.button, autofire.outputs.clone());
+                    let offset_ms = autofire.offset_ms;
+                    if down {
+                        self.press(&outputs, button, offset_ms, at, out);
+                    } else {
+                        self.release(&outputs, button, offset_ms, at, out);
+                    }
+                    continue;
+                }
+            };
+            let running = &mut self.macros[index];
+            let holder = Holder::Macro(running.button);
+            let action = match op {
+                MacroOp::Press(target) => {
+                    running.held.push(target.clone());
+                    self.held
+                        .press(&target, holder)
+                        .then(|| press_action(target))
+                }
+                MacroOp::Release(target) => {
+                    running.held.retain(|held| *held != target);
+                    self.held
+                        .release(&target, holder)
+                        .then(|| release_action(target))
+                }
+                MacroOp::Text(text) => Some(OutputAction::Text(text)),
+            };
+            if let Some(action) = action {
+                if self.debug {
+                    println!("[macro] button {}: {:?}", running.button, action);
+                }
+                let offset_ms = running.offset_ms;
+                self.emit(at, offset_ms, action, out);
+            }
+        }
+
+        // Finished macros let go of anything they left held
+        while let Some(index) = self.macros.iter().position(|m| m.is_done(now)) {
+            let running = self.macros.remove(index);
+            self.release_macro_holds(running, now, out);
+        }
+    }
+}
+
+impl OutputAdapter for ButtonMapper {
+    fn handle_button(&mut self, event: &ButtonEvent, out: &mut Vec<TimedAction>) {
+        // Macro steps that came due before this event go out first
+        self.advance(event.at, out);
+
+        let mut resolved = Vec::new();
+        self.layers.resolve(event, &self.buttons, &mut resolved);
+        for (button, output) in resolved {
+            self.apply(button, output, event.at, out);
+        }
+
+        self.advance(event.at, out);
+    }
+
+    fn poll(&mut self, now: Instant, out: &mut Vec<TimedAction>) {
+        self.advance(now, out);
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let long_presses = self.long_presses.iter().map(|(_, deadline, ..)| *deadline);
+        let steps = self.macros.iter().filter_map(|m| m.next_deadline());
+        let autofire = self.autofire.iter().map(|a| a.next_deadline());
+        let gestures = self.gestures.next_deadline();
+        long_presses
+            .chain(steps)
+            .chain(autofire)
+            .chain(gestures)
+            .min()
+    }
+
+    fn release_all(&mut self, now: Instant, out: &mut Vec<TimedAction>) {
+        self.layers.reset();
+        self.gestures.reset();
+        self.macros.clear();
+        self.long_presses.clear();
+        self.canceling.clear();
+        self.autofire.clear();
+        for (button, _) in self.latched.drain() {
+            if self.debug {
+                println!("[latch] button {} unlatched (release all)", button);
+            }
+        }
+        for target in self.held.take_all() {
+            if self.debug {
+                println!(
+                    "[{}] release {} (release all)",
+                    device_label(&target),
+                    target
+                );
+            }
+            self.emit_at(now, release_action(target), out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyCode;
+    use crate::types::{MacroStep, OutputType, Turbo, X360ButtonEntry, X360ButtonName};
+
+    fn key(name: &str) -> OutputTarget {
+        OutputTarget::Key(KeyCode::parse(name).unwrap())
+    }
+
+    fn mapping(buttons: Vec<(u8, Vec<OutputTarget>)>) -> MappingConfig {
+        MappingConfig {
+            name: None,
+            output: OutputType::Keyboard,
+            buttons: buttons
+                .into_iter()
+                .map(|(id, outputs)| {
+                    let entry = ButtonEntry {
+                        outputs,
+                        ..Default::default()
+                    };
+                    (id.to_string(), entry)
+                })
+                .collect(),
+            filters: Vec::new(),
+            rules: Vec::new(),
+            layers: Vec::new(),
+            offsets: DeviceOffsets::default(),
+            side: None,
+            mirror: None,
+        }
+    }
+
+    fn event(id: u8, pressed: bool, at: Instant) -> ButtonEvent {
+        ButtonEvent { id, pressed, at }
+    }
+
+    fn actions(actions: &[TimedAction]) -> Vec<OutputAction> {
+        actions.iter().map(|a| a.action.clone()).collect()
+    }
+
+    #[test]
+    fn shared_key_released_by_last_button() {
+        let mapping = mapping(vec![(3, vec![key("F18")]), (9, vec![key("F18")])]);
+        let mut output = ButtonMapper::new(&mapping, 0, false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        output.handle_button(&event(3, true, start), &mut out);
+        output.handle_button(&event(9, true, start), &mut out);
+        output.handle_button(&event(3, false, start), &mut out);
+        assert_eq!(
+            actions(&out),
+            vec![OutputAction::KeyDown(KeyCode::Function(18))]
+        );
+
+        output.handle_button(&event(9, false, start), &mut out);
+        assert_eq!(
+            actions(&out)[1..],
+            [OutputAction::KeyUp(KeyCode::Function(18))]
+        );
+    }
+
+    #[test]
+    fn offset_delays_actions_in_order() {
+        let mapping = mapping(vec![(1, vec![key("A")]), (2, vec![key("B")])]);
+        let mut output = ButtonMapper::new(&mapping, 10, false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        output.handle_button(&event(1, true, start), &mut out);
+        output.handle_button(&event(2, true, start + Duration::from_millis(1)), &mut out);
+        let deadlines: Vec<Instant> = out.iter().map(|a| a.at).collect();
+        assert_eq!(
+            deadlines,
+            vec![
+                start + Duration::from_millis(10),
+                start + Duration::from_millis(11)
+            ]
+        );
+    }
+
+    #[test]
+    fn negative_offset_delays_everything_else() {
+        let pad_a = X360ButtonEntry::Button {
+            name: X360ButtonName::A,
+        };
+        let mut mapping = mapping(vec![
+            (1, vec![key("Up")]),
+            (2, vec![key("A")]),
+            (3, vec![OutputTarget::Pad(pad_a)]),
+        ]);
+        mapping.buttons.get_mut("1").unwrap().offset_ms = -5;
+        mapping.offsets.x360 = 2;
+        let mut output = ButtonMapper::new(&mapping, 10, false);
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut out = Vec::new();
+
+        output.handle_button(&event(2, true, start), &mut out);
+        output.handle_button(&event(1, true, start), &mut out);
+        output.handle_button(&event(3, true, start), &mut out);
+        let deadlines: Vec<Instant> = out.iter().map(|a| a.at).collect();
+        assert_eq!(deadlines, vec![ms(15), ms(10), ms(17)]);
+    }
+
+    #[test]
+    fn chord_presses_in_order_and_releases_in_reverse() {
+        let pad_a = X360ButtonEntry::Button {
+            name: X360ButtonName::A,
+        };
+        let mapping = mapping(vec![(
+            0,
+            vec![key("Control"), key("F1"), OutputTarget::Pad(pad_a.clone())],
+        )]);
+        let mut output = ButtonMapper::new(&mapping, 0, false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        output.handle_button(&event(0, true, start), &mut out);
+        output.handle_button(&event(0, false, start), &mut out);
+        assert_eq!(
+            actions(&out),
+            vec![
+                OutputAction::KeyDown(KeyCode::Control),
+                OutputAction::KeyDown(KeyCode::Function(1)),
+                OutputAction::PadDown(pad_a.clone()),
+                OutputAction::PadUp(pad_a),
+                OutputAction::KeyUp(KeyCode::Function(1)),
+                OutputAction::KeyUp(KeyCode::Control),
+            ]
+        );
+    }
+
+    fn macro_entry(trigger: MacroTrigger, steps: Vec<MacroStep>) -> ButtonEntry {
+        ButtonEntry {
+            macros: vec![Macro {
+                trigger,
+                tap_ms: 20,
+                steps,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pressing_again_cancels_running_macro() {
+        let mut mapping = mapping(Vec::new());
+        let entry = macro_entry(
+            MacroTrigger::Press,
+            vec![
+                MacroStep::Down(key("Shift")),
+                MacroStep::Delay(100),
+                MacroStep::Tap(key("Return")),
+            ],
+        );
+        mapping.buttons.insert("7".to_string(), entry);
+        let mut output = ButtonMapper::new(&mapping, 0, false);
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut out = Vec::new();
+
+        output.handle_button(&event(7, true, start), &mut out);
+        output.handle_button(&event(7, false, ms(10)), &mut out);
+        assert_eq!(output.next_deadline(), Some(ms(100)));
+
+        output.handle_button(&event(7, true, ms(50)), &mut out);
+        output.handle_button(&event(7, false, ms(60)), &mut out);
+        assert_eq!(output.next_deadline(), None);
+        assert_eq!(
+            actions(&out),
+            vec![
+                OutputAction::KeyDown(KeyCode::Shift),
+                OutputAction::KeyUp(KeyCode::Shift),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_press_macro_needs_hold_time() {
+        let mut mapping = mapping(Vec::new());
+        let entry = macro_entry(
+            MacroTrigger::LongPress(500),
+            vec![MacroStep::Text("abc".to_string())],
+        );
+        mapping.buttons.insert("2".to_string(), entry);
+        let mut output = ButtonMapper::new(&mapping, 0, false);
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut out = Vec::new();
+
+        output.handle_button(&event(2, true, start), &mut out);
+        output.handle_button(&event(2, false, ms(300)), &mut out);
+        assert_eq!(output.next_deadline(), None);
+
+        output.handle_button(&event(2, true, ms(1000)), &mut out);
+        assert_eq!(output.next_deadline(), Some(ms(1500)));
+        output.poll(ms(1500), &mut out);
+        assert_eq!(actions(&out), vec![OutputAction::Text("abc".to_string())]);
+        assert_eq!(out[0].at, ms(1500));
+    }
+
+    #[test]
+    fn toggle_turbo_fires_until_pressed_again() {
+        let mut mapping = mapping(vec![(5, vec![key("Down")])]);
+        mapping.buttons.get_mut("5").unwrap().turbo = Some(Turbo {
+            mode: TurboMode::Toggle,
+            period: Duration::from_millis(100),
+            down: Duration::from_millis(50),
+        });
+        let mut output = ButtonMapper::new(&mapping, 0, false);
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut out = Vec::new();
+
+        output.handle_button(&event(5, true, start), &mut out);
+        output.handle_button(&event(5, false, ms(10)), &mut out);
+        output.poll(ms(120), &mut out);
+        assert_eq!(output.next_deadline(), Some(ms(150)));
+
+        output.handle_button(&event(5, true, ms(130)), &mut out);
+        output.handle_button(&event(5, false, ms(140)), &mut out);
+        assert_eq!(output.next_deadline(), None);
+        let down = OutputAction::KeyDown(KeyCode::Down);
+        let up = OutputAction::KeyUp(KeyCode::Down);
+        assert_eq!(actions(&out), vec![down.clone(), up.clone(), down, up]);
+        let deadlines: Vec<Instant> = out.iter().map(|a| a.at).collect();
+        assert_eq!(deadlines, vec![ms(0), ms(50), ms(100), ms(130)]);
+    }
+
+    #[test]
+    fn latch_holds_until_next_press_or_release_all() {
+        let mut mapping = mapping(vec![(6, vec![key("F16")])]);
+        mapping.buttons.get_mut("6").unwrap().latch = true;
+        let mut output = ButtonMapper::new(&mapping, 0, false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        output.handle_button(&event(6, true, start), &mut out);
+        output.handle_button(&event(6, false, start), &mut out);
+        assert_eq!(
+            actions(&out),
+            vec![OutputAction::KeyDown(KeyCode::Function(16))]
+        );
+
+        output.handle_button(&event(6, true, start), &mut out);
+        output.handle_button(&event(6, false, start), &mut out);
+        output.handle_button(&event(6, true, start), &mut out);
+        output.release_all(start, &mut out);
+        assert_eq!(
+            actions(&out)[1..],
+            [
+                OutputAction::KeyUp(KeyCode::Function(16)),
+                OutputAction::KeyDown(KeyCode::Function(16)),
+                OutputAction::KeyUp(KeyCode::Function(16)),
+            ]
+        );
+    }
+}
@@ -1,4 +1,36 @@
+mod gestures;
+mod hold;
 pub mod keyboard;
-
+mod layers;
+mod macros;
+pub mod mapper;
+mod turbo;
 #[cfg(target_os = "windows")]
 pub mod x360;
+
+use crate::types::{OutputAction, OutputDevice};
+
+/// Drives several devices at once; each one ignores actions meant for the others.
+pub struct MultiDevice {
+    devices: Vec<Box<dyn OutputDevice>>,
+}
+
+impl MultiDevice {
+    pub fn new(devices: Vec<Box<dyn OutputDevice>>) -> Self {
+        Self { devices }
+    }
+}
+
+impl OutputDevice for MultiDevice {
+    fn execute(&mut self, action: &OutputAction) {
+        for device in &mut self.devices {
+            device.execute(action);
+        }
+    }
+
+    fn shutdown(&mut self) {
+        for device in &mut self.devices {
+            device.shutdown();
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::Instant;
+
+use crate::types::{OutputTarget, Turbo, TurboMode};
+
+/// Autofire running for one button: its outputs go down at the start of every cycle and
+/// up once the cycle's down time has passed.
+pub struct Autofire {
+    pub button: u8,
+    pub outputs: Vec<OutputTarget>,
+    /// Offset of the entry the outputs belong to
+    pub offset_ms: i64,
+    config: Turbo,
+    cycle_start: Instant,
+    down: bool,
+}
+
+impl Autofire {
+    /// Start autofire with the first press due at `at`.
+    pub fn start(
+        button: u8,
+        outputs: Vec<OutputTarget>,
+        offset_ms: i64,
+        config: Turbo,
+        at: Instant,
+    ) -> Self {
+        Self {
+            button,
+            outputs,
+            offset_ms,
+            config,
+            cycle_start: at,
+            down: false,
+        }
+    }
+
+    pub fn mode(&self) -> TurboMode {
+        self.config.mode
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.down
+    }
+
+    pub fn next_deadline(&self) -> Instant {
+        if self.down {
+            self.cycle_start + self.config.down
+        } else {
+            self.cycle_start
+        }
+    }
+
+    /// Move the press (true) and release (false) edges due at `now` to `out`.
+    pub fn take_due(&mut self, now: Instant, out: &mut Vec<(Instant, bool)>) {
+        while self.next_deadline() <= now {
+            let at = self.next_deadline();
+            if self.down {
+                self.cycle_start += self.config.period;
+            }
+            self.down = !self.down;
+            out.push((at, self.down));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn edges_follow_rate_and_duty_cycle() {
+        let config = Turbo {
+            mode: TurboMode::Hold,
+            period: Duration::from_millis(100),
+            down: Duration::from_millis(30),
+        };
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut autofire = Autofire::start(0, Vec::new(), 0, config, start);
+
+        let mut edges = Vec::new();
+        autofire.take_due(ms(229), &mut edges);
+        assert_eq!(
+            edges,
+            vec![
+                (ms(0), true),
+                (ms(30), false),
+                (ms(100), true),
+                (ms(130), false),
+                (ms(200), true),
+            ]
+        );
+        assert!(autofire.is_down());
+        assert_eq!(autofire.next_deadline(), ms(230));
+    }
+}
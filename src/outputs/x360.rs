@@ -1,24 +1,20 @@
-use crate::types::{
-    ButtonEvent, DpadDirection, OutputAdapter, TriggerName, X360ButtonEntry, X360ButtonName,
-    X360Mapping,
-};
+use std::collections::HashSet;
 
 use vigem_client::{Client, TargetId, XButtons, XGamepad, Xbox360Wired};
 
-pub struct X360Output {
+use crate::types::{
+    DpadDirection, OutputAction, OutputDevice, TriggerName, X360ButtonEntry, X360ButtonName,
+};
+
+/// Virtual Xbox 360 pad provided by ViGEmBus.
+pub struct X360Device {
     target: Xbox360Wired<Client>,
     gamepad: XGamepad,
-    mapping: X360Mapping,
-    dpad_up: bool,
-    dpad_down: bool,
-    dpad_left: bool,
-    dpad_right: bool,
-    offset_ms: u64,
-    debug: bool,
+    dpad: HashSet<DpadDirection>,
 }
 
-impl X360Output {
-    pub fn new(mapping: X360Mapping, offset_ms: u64, debug: bool) -> Result<Self, String> {
+impl X360Device {
+    pub fn new() -> Result<Self, String> {
         let client =
             Client::connect().map_err(|e| format!("Failed to connect to ViGEmBus: {:?}", e))?;
         let id = TargetId::XBOX360_WIRED;
@@ -33,13 +29,7 @@ impl X360Output {
         Ok(Self {
             target,
             gamepad: XGamepad::default(),
-            mapping,
-            dpad_up: false,
-            dpad_down: false,
-            dpad_left: false,
-            dpad_right: false,
-            offset_ms,
-            debug,
+            dpad: HashSet::new(),
         })
     }
 
@@ -71,17 +61,17 @@ impl X360Output {
 
     fn update_dpad(&mut self) {
         // Horizontal: left = -32768, right = 32767, center = 0
-        let horz: i16 = if self.dpad_left {
+        let horz: i16 = if self.dpad.contains(&DpadDirection::Left) {
             -32768
-        } else if self.dpad_right {
+        } else if self.dpad.contains(&DpadDirection::Right) {
             32767
         } else {
             0
         };
         // Vertical: up = 32767, down = -32768, center = 0
-        let vert: i16 = if self.dpad_up {
+        let vert: i16 = if self.dpad.contains(&DpadDirection::Up) {
             32767
-        } else if self.dpad_down {
+        } else if self.dpad.contains(&DpadDirection::Down) {
             -32768
         } else {
             0
@@ -100,45 +90,40 @@ impl X360Output {
         }
         let _ = self.target.update(&self.gamepad);
     }
-}
-
-impl OutputAdapter for X360Output {
-    fn handle_button(&mut self, event: &ButtonEvent) {
-        let entry = match self.mapping.buttons.get(&event.id.to_string()) {
-            Some(e) => e.clone(),
-            None => return,
-        };
 
-        if self.debug {
-            let action = if event.pressed { "press" } else { "release" };
-            println!("[x360] {} button {} ", action, event.id);
-        }
-
-        if self.offset_ms > 0 {
-            std::thread::sleep(std::time::Duration::from_millis(self.offset_ms));
-        }
-
-        match &entry {
+    fn apply(&mut self, entry: &X360ButtonEntry, pressed: bool) {
+        match entry {
             X360ButtonEntry::Trigger { trigger } => {
-                self.set_trigger(trigger, event.pressed);
+                self.set_trigger(trigger, pressed);
             }
             X360ButtonEntry::Dpad { direction } => {
-                match direction {
-                    DpadDirection::Up => self.dpad_up = event.pressed,
-                    DpadDirection::Down => self.dpad_down = event.pressed,
-                    DpadDirection::Left => self.dpad_left = event.pressed,
-                    DpadDirection::Right => self.dpad_right = event.pressed,
+                if pressed {
+                    self.dpad.insert(direction.clone());
+                } else {
+                    self.dpad.remove(direction);
                 }
                 self.update_dpad();
             }
             X360ButtonEntry::Button { name } => {
-                self.set_button(name, event.pressed);
+                self.set_button(name, pressed);
             }
         }
     }
+}
+
+impl OutputDevice for X360Device {
+    fn execute(&mut self, action: &OutputAction) {
+        match action {
+            OutputAction::PadDown(entry) => self.apply(entry, true),
+            OutputAction::PadUp(entry) => self.apply(entry, false),
+            OutputAction::KeyDown(_) | OutputAction::KeyUp(_) | OutputAction::Text(_) => {}
+        }
+    }
 
     fn shutdown(&mut self) {
-        // Reset all state
+        // Reset all state. On panic or forced exit the pad is removed when the
+        // ViGEm handle closes, so it registers no emergency release callback to drop here.
+        self.dpad.clear();
         self.gamepad = XGamepad::default();
         let _ = self.target.update(&self.gamepad);
         let _ = self.target.unplug();
@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::filters::FilterChain;
+use crate::keys::KeyCode;
+use crate::scheduler::Scheduler;
+use crate::serial::{run_serial_loop, SerialEvent, SerialOptions};
+use crate::timer::{recv_until, Wake};
+use crate::types::{ButtonEvent, OutputAction, OutputAdapter, OutputDevice, Outputs, TimedAction};
+
+/// Events the reader may get ahead of the processing stage before it has to wait.
+const INPUT_CAPACITY: usize = 256;
+
+/// Actions the processing stage may get ahead of the output stage before it has to wait.
+const OUTPUT_CAPACITY: usize = 256;
+
+/// Output actions running later than this are reported in debug mode.
+const LATE_WARNING: Duration = Duration::from_millis(1);
+
+/// Creates the output device for a set of outputs on the output thread (input devices are not
+/// always `Send`).
+pub type DeviceFactory = Box<dyn Fn(Outputs) -> Result<Box<dyn OutputDevice>, String> + Send>;
+
+/// The processing stage's logic for one mapping: its filters, then its output adapter, and the
+/// devices the adapter's actions are meant for.
+pub struct Stage {
+    pub filters: FilterChain,
+    pub adapter: Box<dyn OutputAdapter>,
+    pub outputs: Outputs,
+}
+
+/// Offers a replacement `Stage` while running, e.g. after the mapping file changed.
+/// Polled by the processing stage between events, like a filter.
+pub trait StageSource {
+    /// See a button event as it arrives from the controller, before any filter.
+    fn on_event(&mut self, _event: &ButtonEvent) {}
+    fn next_deadline(&self) -> Option<Instant>;
+    /// The stage to switch to at `now`, if any. It only takes effect once confirmed.
+    fn poll(&mut self, now: Instant) -> Option<Stage>;
+    /// The stage from the last `poll` is running.
+    fn confirm(&mut self) {}
+    /// The stage from the last `poll` was dropped (its devices couldn't be created); the
+    /// previous one keeps running.
+    fn reject(&mut self) {}
+}
+
+/// Latency counters for one stage boundary, printed on shutdown in debug mode.
+pub struct StageStats {
+    label: &'static str,
+    count: u64,
+    total: Duration,
+    max: Duration,
+    stalls: u64,
+}
+
+impl StageStats {
+    pub fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+            stalls: 0,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Count a send that had to wait for the next stage.
+    pub fn stall(&mut self) {
+        self.stalls += 1;
+    }
+
+    pub fn summary(&self) -> String {
+        let avg = if self.count == 0 {
+            Duration::ZERO
+        } else {
+            self.total / self.count as u32
+        };
+        format!(
+            "{}: {} items, avg {:.3} ms, max {:.3} ms, {} stalls",
+            self.label,
+            self.count,
+            avg.as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0,
+            self.stalls
+        )
+    }
+}
+
+/// Send without blocking unless the receiving stage is a full channel behind.
+/// Returns `Ok(true)` if the send had to wait (backpressure), `Err` if the receiver is gone.
+fn send_with_backpressure<T>(tx: &SyncSender<T>, item: T) -> Result<bool, ()> {
+    match tx.try_send(item) {
+        Ok(()) => Ok(false),
+        Err(TrySendError::Full(item)) => tx.send(item).map(|_| true).map_err(|_| ()),
+        Err(TrySendError::Disconnected(_)) => Err(()),
+    }
+}
+
+/// Run the input pipeline until the serial loop ends:
+///
+/// - reader thread: reads and timestamps serial events
+/// - processing stage (this thread): runs events through the stage's filters, then its
+///   mapping logic, switching to whatever stage `source` offers
+/// - output thread: performs device actions at their scheduled time
+///
+/// Stages are connected by bounded channels, so a slow device call never delays serial reads
+/// unless the output stage falls a whole channel behind.
+pub fn run_pipeline(
+    serial: SerialOptions,
+    stage: Stage,
+    mut source: Option<Box<dyn StageSource>>,
+    create_device: DeviceFactory,
+) -> Result<(), String> {
+    let debug = serial.debug;
+    let mut output = OutputStage::spawn(create_device, stage.outputs, debug)?;
+
+    let (input_tx, input_rx) = mpsc::sync_channel(INPUT_CAPACITY);
+    let reader = spawn_reader(serial, input_tx);
+
+    let source = source.as_mut().map(|s| s.as_mut() as &mut dyn StageSource);
+    process(stage, source, &input_rx, &mut output, debug);
+
+    output.finish();
+    reader
+        .join()
+        .unwrap_or_else(|_| Err("Serial reader thread panicked".to_string()))
+}
+
+fn spawn_reader(
+    options: SerialOptions,
+    events: SyncSender<SerialEvent>,
+) -> JoinHandle<Result<(), String>> {
+    std::thread::spawn(move || {
+        let mut stats = StageStats::new("reader backpressure");
+        let result = run_serial_loop(&options, |event| {
+            if let Ok(true) = send_with_backpressure(&events, event) {
+                stats.stall();
+                if options.debug {
+                    println!("[pipeline] processing stage is behind, reader waited");
+                }
+            }
+        });
+        if options.debug && stats.stalls > 0 {
+            println!("[pipeline] reader stalled {} times", stats.stalls);
+        }
+        result
+    })
+}
+
+/// Processing stage: feed events through the filters and the adapter, and hand the adapter's
+/// actions to the output stage.
+/// Returns once the reader is gone, after releasing everything the adapter holds.
+fn process(
+    stage: Stage,
+    mut source: Option<&mut dyn StageSource>,
+    input: &Receiver<SerialEvent>,
+    output: &mut OutputStage,
+    debug: bool,
+) {
+    let Stage {
+        mut filters,
+        mut adapter,
+        mut outputs,
+    } = stage;
+    let mut stats = StageStats::new("reader -> processor");
+    let mut events = Vec::new();
+    let mut actions = Vec::new();
+
+    loop {
+        let deadline = [
+            filters.next_deadline(),
+            adapter.next_deadline(),
+            source.as_ref().and_then(|s| s.next_deadline()),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        match recv_until(input, deadline) {
+            Wake::Message(SerialEvent::Button(event)) => {
+                stats.record(event.at.elapsed());
+                if let Some(source) = source.as_mut() {
+                    source.on_event(&event);
+                }
+                filters.on_event(event, &mut events);
+            }
+            Wake::Message(SerialEvent::LinkLost) => {
+                // Releases of buttons held on the controller may never arrive
+                if debug {
+                    println!("[pipeline] serial link lost, releasing all outputs");
+                }
+                adapter.release_all(Instant::now(), &mut actions);
+                // Filters and rules would otherwise still treat those buttons as held
+                filters.reset();
+            }
+            Wake::Deadline => {}
+            Wake::Disconnected => break,
+        }
+        let now = Instant::now();
+        filters.poll(now, &mut events);
+        for event in events.drain(..) {
+            adapter.handle_button(&event, &mut actions);
+        }
+        adapter.poll(now, &mut actions);
+        let next = source.as_deref_mut().and_then(|s| s.poll(now));
+        if let (Some(source), Some(next)) = (source.as_deref_mut(), next) {
+            // Outputs held under the old mapping may have no release under the new one
+            adapter.release_all(now, &mut actions);
+            output.send_all(&mut actions);
+            if next.outputs != outputs {
+                if let Err(e) = output.switch(next.outputs) {
+                    eprintln!(
+                        "Failed to switch output devices, keeping the previous mapping: {}",
+                        e
+                    );
+                    source.reject();
+                    continue;
+                }
+            }
+            source.confirm();
+            Stage {
+                filters,
+                adapter,
+                outputs,
+            } = next;
+        }
+        output.send_all(&mut actions);
+    }
+
+    adapter.release_all(Instant::now(), &mut actions);
+    output.send_all(&mut actions);
+
+    if debug {
+        println!("[pipeline] {}", stats.summary());
+        for summary in filters.summaries() {
+            println!("[filter] {}", summary);
+        }
+    }
+}
+
+/// What the processing stage sends the output stage.
+enum OutputMessage {
+    Action(TimedAction),
+    /// Replace the device with one for these outputs, once everything pending has run.
+    Switch(Outputs, Sender<Result<(), String>>),
+}
+
+/// Output stage: owns the device and performs each action at its scheduled time.
+struct OutputStage {
+    messages: SyncSender<OutputMessage>,
+    handle: JoinHandle<()>,
+    stats: StageStats,
+    debug: bool,
+}
+
+impl OutputStage {
+    /// Create the device on a new thread and wait until it is ready.
+    fn spawn(create_device: DeviceFactory, outputs: Outputs, debug: bool) -> Result<Self, String> {
+        let (messages, rx) = mpsc::sync_channel::<OutputMessage>(OUTPUT_CAPACITY);
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        let handle = std::thread::spawn(move || {
+            let device = match create_device(outputs) {
+                Ok(device) => {
+                    let _ = ready_tx.send(Ok(()));
+                    device
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            run_output_loop(device, &create_device, &rx, debug);
+        });
+
+        ready_rx
+            .recv()
+            .map_err(|_| "Output thread exited during startup".to_string())??;
+
+        Ok(Self {
+            messages,
+            handle,
+            stats: StageStats::new("processor -> output"),
+            debug,
+        })
+    }
+
+    fn send_all(&mut self, actions: &mut Vec<TimedAction>) {
+        for action in actions.drain(..) {
+            if let Ok(true) = send_with_backpressure(&self.messages, OutputMessage::Action(action))
+            {
+                self.stats.stall();
+                if self.debug {
+                    println!("[pipeline] output stage is behind, processor waited");
+                }
+            }
+        }
+    }
+
+    /// Replace the device with one for `outputs`, after the actions already sent have run.
+    /// The old device stays if the new one can't be created.
+    fn switch(&mut self, outputs: Outputs) -> Result<(), String> {
+        let (done_tx, done_rx) = mpsc::channel();
+        self.messages
+            .send(OutputMessage::Switch(outputs, done_tx))
+            .map_err(|_| "Output thread has stopped".to_string())?;
+        done_rx
+            .recv()
+            .map_err(|_| "Output thread has stopped".to_string())?
+    }
+
+    /// Stop the output loop; the device releases everything before this returns.
+    fn finish(self) {
+        drop(self.messages);
+        let _ = self.handle.join();
+        if self.debug && self.stats.stalls > 0 {
+            println!("[pipeline] processor stalled {} times", self.stats.stalls);
+        }
+    }
+}
+
+fn run_output_loop(
+    mut device: Box<dyn OutputDevice>,
+    create_device: &DeviceFactory,
+    rx: &Receiver<OutputMessage>,
+    debug: bool,
+) {
+    let mut pending = Scheduler::new();
+    let mut stats = StageStats::new("output lateness");
+    // Key-down times, to report measured hold durations (e.g. tap keys) in debug mode
+    let mut key_down_at: HashMap<KeyCode, Instant> = HashMap::new();
+
+    let mut execute = |device: &mut Box<dyn OutputDevice>, at: Instant, action: OutputAction| {
+        device.execute(&action);
+        let now = Instant::now();
+        let late = now.saturating_duration_since(at);
+        stats.record(late);
+        if !debug {
+            return;
+        }
+        if late > LATE_WARNING {
+            println!(
+                "[output] {:?} ran {:.2} ms late",
+                action,
+                late.as_secs_f64() * 1000.0
+            );
+        }
+        match action {
+            OutputAction::KeyDown(key) => {
+                key_down_at.insert(key, now);
+            }
+            OutputAction::KeyUp(key) => {
+                if let Some(down_at) = key_down_at.remove(&key) {
+                    println!(
+                        "[output] {} held {:.2} ms",
+                        key,
+                        (now - down_at).as_secs_f64() * 1000.0
+                    );
+                }
+            }
+            _ => {}
+        }
+    };
+
+    loop {
+        match recv_until(rx, pending.next_deadline()) {
+            Wake::Message(OutputMessage::Action(timed)) => pending.schedule(timed.at, timed.action),
+            Wake::Message(OutputMessage::Switch(outputs, done)) => {
+                // The old mapping's releases go to the old device, however far ahead they are
+                for (at, action) in pending.take_all() {
+                    execute(&mut device, at, action);
+                }
+                let result = create_device(outputs).map(|new_device| {
+                    device.shutdown();
+                    device = new_device;
+                });
+                let _ = done.send(result);
+            }
+            Wake::Deadline => {}
+            Wake::Disconnected => break,
+        }
+        for (at, action) in pending.take_due(Instant::now()) {
+            execute(&mut device, at, action);
+        }
+    }
+
+    // Flush whatever is left (final releases) in order, without waiting
+    for (at, action) in pending.take_all() {
+        execute(&mut device, at, action);
+    }
+    device.shutdown();
+
+    if debug {
+        println!("[pipeline] {}", stats.summary());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::{default_maps, load_watched_mapping};
+    use crate::outputs::mapper::ButtonMapper;
+    use crate::profiles::ProfileSwitcher;
+    use crate::reload::MappingWatcher;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn stats_summary_reports_average_and_max() {
+        let mut stats = StageStats::new("test");
+        stats.record(Duration::from_millis(1));
+        stats.record(Duration::from_millis(3));
+        stats.stall();
+        assert_eq!(
+            stats.summary(),
+            "test: 2 items, avg 2.000 ms, max 3.000 ms, 1 stalls"
+        );
+    }
+
+    /// Logs what it executes, under its name.
+    struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl OutputDevice for Recorder {
+        fn execute(&mut self, action: &OutputAction) {
+            let entry = format!("{} {:?}", self.name, action);
+            self.log.lock().unwrap().push(entry);
+        }
+
+        fn shutdown(&mut self) {
+            let entry = format!("{} shutdown", self.name);
+            self.log.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn switching_devices_runs_pending_actions_on_the_old_device_first() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let device_log = log.clone();
+        let create_device: DeviceFactory = Box::new(move |outputs: Outputs| {
+            let name = match (outputs.keyboard, outputs.pad) {
+                (true, false) => "keyboard",
+                (true, true) => "both",
+                _ => return Err("no pad here".to_string()),
+            };
+            let log = device_log.clone();
+            Ok(Box::new(Recorder { name, log }) as Box<dyn OutputDevice>)
+        });
+        let keyboard = Outputs {
+            keyboard: true,
+            pad: false,
+        };
+        let mut output = OutputStage::spawn(create_device, keyboard, false).unwrap();
+
+        let key = KeyCode::parse("A").unwrap();
+        let mut actions = vec![TimedAction {
+            at: Instant::now() + Duration::from_secs(10),
+            action: OutputAction::KeyUp(key),
+        }];
+        output.send_all(&mut actions);
+        let both = Outputs {
+            keyboard: true,
+            pad: true,
+        };
+        output.switch(both).unwrap();
+        let pad = Outputs {
+            keyboard: false,
+            pad: true,
+        };
+        assert_eq!(output.switch(pad), Err("no pad here".to_string()));
+        output.finish();
+
+        let expected = vec![
+            format!("keyboard {:?}", OutputAction::KeyUp(key)),
+            "keyboard shutdown".to_string(),
+            "both shutdown".to_string(),
+        ];
+        assert_eq!(*log.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn failed_device_switch_keeps_the_running_profile() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let device_log = log.clone();
+        let create_device: DeviceFactory = Box::new(move |outputs: Outputs| {
+            if outputs.pad {
+                return Err("no pad here".to_string());
+            }
+            let log = device_log.clone();
+            Ok(Box::new(Recorder {
+                name: "keyboard",
+                log,
+            }) as Box<dyn OutputDevice>)
+        });
+        let load = |name: &str| {
+            let path = default_maps()
+                .remove(name)
+                .ok_or_else(|| format!("Unknown mode \"{}\"", name))?;
+            let (mapping, files) = load_watched_mapping(&path, &[])?;
+            let stage = Stage {
+                filters: FilterChain::new(),
+                adapter: Box::new(ButtonMapper::new(&mapping, 0, false)),
+                outputs: mapping.outputs(),
+            };
+            Ok((stage, files))
+        };
+        let (stage, files) = load("iidx").unwrap();
+        let watched = files.clone();
+        let watcher = MappingWatcher::new("iidx", files, Box::new(load));
+        let profiles = vec!["iidx".to_string(), "x360".to_string()];
+        let mut switcher =
+            ProfileSwitcher::new(profiles, Some(0), vec![0, 3], Duration::ZERO, watcher);
+        let mut output = OutputStage::spawn(create_device, stage.outputs, false).unwrap();
+
+        // Hold the switch buttons: the x360 profile loads, but its pad can't be created
+        let (tx, rx) = mpsc::sync_channel(INPUT_CAPACITY);
+        let at = Instant::now();
+        for id in [0, 3] {
+            let event = ButtonEvent {
+                id,
+                pressed: true,
+                at,
+            };
+            tx.send(SerialEvent::Button(event)).unwrap();
+        }
+        drop(tx);
+        process(stage, Some(&mut switcher), &rx, &mut output, false);
+        output.finish();
+
+        assert_eq!(switcher.current(), Some(0));
+        let (mapping, files) = switcher.watcher().watched();
+        assert_eq!(mapping, "iidx");
+        assert_eq!(files, watched);
+        let log = log.lock().unwrap();
+        assert_eq!(log.last().map(String::as_str), Some("keyboard shutdown"));
+    }
+
+    #[test]
+    fn backpressure_waits_only_when_full() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        assert_eq!(send_with_backpressure(&tx, 1), Ok(false));
+
+        let consumer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            (rx.recv().unwrap(), rx.recv().unwrap())
+        });
+        assert_eq!(send_with_backpressure(&tx, 2), Ok(true));
+        assert_eq!(consumer.join().unwrap(), (1, 2));
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::pipeline::{Stage, StageSource};
+use crate::reload::MappingWatcher;
+use crate::types::ButtonEvent;
+
+/// Parse switch buttons written as `0+3`.
+pub fn parse_switch_buttons(spec: &str) -> Result<Vec<u8>, String> {
+    let mut buttons = Vec::new();
+    for part in spec.split('+').map(str::trim) {
+        let button = part.parse::<u8>().map_err(|_| {
+            format!(
+                "Invalid switch buttons \"{}\": \"{}\" is not a button number",
+                spec, part
+            )
+        })?;
+        if !buttons.contains(&button) {
+            buttons.push(button);
+        }
+    }
+    Ok(buttons)
+}
+
+/// Switches to the next profile (mapping mode or path) once the switch buttons have been held
+/// together for `hold`, and hot-reloads the running profile in between.
+pub struct ProfileSwitcher {
+    profiles: Vec<String>,
+    /// Index of the running profile; `None` until the first switch if the starting mapping
+    /// isn't one of them
+    current: Option<usize>,
+    buttons: Vec<u8>,
+    hold: Duration,
+    /// Switch buttons currently down on the controller
+    held: HashSet<u8>,
+    /// When the held combination switches; cleared when one of its buttons is released
+    switch_at: Option<Instant>,
+    /// Index of the profile whose stage was offered but not confirmed yet
+    pending: Option<usize>,
+    watcher: MappingWatcher,
+}
+
+impl ProfileSwitcher {
+    pub fn new(
+        profiles: Vec<String>,
+        current: Option<usize>,
+        buttons: Vec<u8>,
+        hold: Duration,
+        watcher: MappingWatcher,
+    ) -> Self {
+        Self {
+            profiles,
+            current,
+            buttons,
+            hold,
+            held: HashSet::new(),
+            switch_at: None,
+            pending: None,
+            watcher,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    #[cfg(test)]
+    pub fn watcher(&self) -> &MappingWatcher {
+        &self.watcher
+    }
+
+    /// Load the next profile, skipping (and reporting) any that fail to load.
+    fn switch(&mut self) -> Option<Stage> {
+        let start = self.current.map_or(0, |i| i + 1);
+        for step in 0..self.profiles.len() {
+            let index = (start + step) % self.profiles.len();
+            if Some(index) == self.current {
+                break;
+            }
+            let profile = &self.profiles[index];
+            match self.watcher.switch_to(profile) {
+                Ok(stage) => {
+                    self.pending = Some(index);
+                    return Some(stage);
+                }
+                Err(e) => eprintln!("Failed to switch to profile {}: {}", profile, e),
+            }
+        }
+        None
+    }
+}
+
+impl StageSource for ProfileSwitcher {
+    fn on_event(&mut self, event: &ButtonEvent) {
+        if !self.buttons.contains(&event.id) {
+            return;
+        }
+        if !event.pressed {
+            self.held.remove(&event.id);
+            self.switch_at = None;
+        } else if self.held.insert(event.id) && self.held.len() == self.buttons.len() {
+            self.switch_at = Some(event.at + self.hold);
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        [self.switch_at, self.watcher.next_deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<Stage> {
+        if self.switch_at.is_some_and(|at| now >= at) {
+            // Fires once per hold; the buttons have to be released to switch again
+            self.switch_at = None;
+            return self.switch();
+        }
+        self.watcher.poll(now)
+    }
+
+    fn confirm(&mut self) {
+        self.watcher.confirm();
+        if let Some(index) = self.pending.take() {
+            println!(
+                "Profile: {} ({}/{})",
+                self.profiles[index],
+                index + 1,
+                self.profiles.len()
+            );
+            self.current = Some(index);
+        }
+    }
+
+    fn reject(&mut self) {
+        self.watcher.reject();
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::FilterChain;
+    use crate::mapping::{default_maps, load_watched_mapping};
+    use crate::outputs::mapper::ButtonMapper;
+    use crate::types::Outputs;
+
+    fn new_switcher(profiles: &[&str], current: Option<usize>) -> ProfileSwitcher {
+        let watcher = MappingWatcher::new(
+            "iidx",
+            Vec::new(),
+            Box::new(|name| {
+                let path = default_maps()
+                    .remove(name)
+                    .ok_or_else(|| format!("Unknown mode \"{}\"", name))?;
+                let (mapping, files) = load_watched_mapping(&path, &[])?;
+                let stage = Stage {
+                    filters: FilterChain::new(),
+                    adapter: Box::new(ButtonMapper::new(&mapping, 0, false)),
+                    outputs: mapping.outputs(),
+                };
+                Ok((stage, files))
+            }),
+        );
+        let profiles = profiles.iter().map(|p| p.to_string()).collect();
+        ProfileSwitcher::new(
+            profiles,
+            current,
+            vec![0, 3],
+            Duration::from_secs(1),
+            watcher,
+        )
+    }
+
+    fn event(id: u8, pressed: bool, at: Instant) -> ButtonEvent {
+        ButtonEvent { id, pressed, at }
+    }
+
+    /// Hold both switch buttons from `at`, and the outputs of the stage switched to, if any.
+    /// The switch is confirmed as if its devices were created.
+    fn hold_switch(switcher: &mut ProfileSwitcher, at: Instant) -> Option<Outputs> {
+        switcher.on_event(&event(0, true, at));
+        switcher.on_event(&event(3, true, at + Duration::from_millis(50)));
+        let stage = switcher.poll(at + Duration::from_millis(1050));
+        if stage.is_some() {
+            switcher.confirm();
+        }
+        switcher.on_event(&event(0, false, at + Duration::from_millis(1100)));
+        switcher.on_event(&event(3, false, at + Duration::from_millis(1100)));
+        stage.map(|s| s.outputs)
+    }
+
+    #[test]
+    fn parses_switch_buttons() {
+        assert_eq!(parse_switch_buttons("0+3").unwrap(), vec![0, 3]);
+        assert_eq!(parse_switch_buttons(" 3 + 0 + 3 ").unwrap(), vec![3, 0]);
+        let err = parse_switch_buttons("0+start").unwrap_err();
+        assert!(err.contains("\"start\" is not a button number"), "{}", err);
+    }
+
+    #[test]
+    fn holding_switch_buttons_cycles_profiles() {
+        let keyboard = Outputs {
+            keyboard: true,
+            pad: false,
+        };
+        let pad = Outputs {
+            keyboard: false,
+            pad: true,
+        };
+        let mut switcher = new_switcher(&["iidx", "popn", "x360"], Some(0));
+        let start = Instant::now();
+
+        // Released before the hold time: nothing happens
+        switcher.on_event(&event(0, true, start));
+        switcher.on_event(&event(3, true, start));
+        switcher.on_event(&event(3, false, start + Duration::from_millis(900)));
+        assert!(switcher.poll(start + Duration::from_millis(1000)).is_none());
+        switcher.on_event(&event(0, false, start + Duration::from_millis(1000)));
+
+        // The hold counts from when the combination is complete
+        switcher.on_event(&event(0, true, start));
+        switcher.on_event(&event(3, true, start + Duration::from_millis(500)));
+        assert!(switcher.poll(start + Duration::from_millis(1000)).is_none());
+        let stage = switcher.poll(start + Duration::from_millis(1500)).unwrap();
+        assert_eq!(stage.outputs, keyboard);
+        assert_eq!(switcher.current, Some(0), "not switched until confirmed");
+        switcher.confirm();
+        assert_eq!(switcher.current, Some(1));
+        assert!(
+            switcher.poll(start + Duration::from_millis(3000)).is_none(),
+            "one switch per hold"
+        );
+        switcher.on_event(&event(0, false, start));
+        switcher.on_event(&event(3, false, start));
+
+        assert_eq!(hold_switch(&mut switcher, start), Some(pad));
+        assert_eq!(hold_switch(&mut switcher, start), Some(keyboard));
+        assert_eq!(switcher.current, Some(0));
+    }
+
+    #[test]
+    fn skips_profiles_that_fail_to_load() {
+        let mut switcher = new_switcher(&["iidx", "missing", "popn"], None);
+        let start = Instant::now();
+
+        assert!(hold_switch(&mut switcher, start).is_some());
+        assert_eq!(switcher.current, Some(0));
+        assert!(hold_switch(&mut switcher, start).is_some());
+        assert_eq!(switcher.current, Some(2));
+
+        let mut broken = new_switcher(&["missing"], None);
+        assert!(hold_switch(&mut broken, start).is_none());
+        assert_eq!(broken.current, None);
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::pipeline::{Stage, StageSource};
+
+/// How often the watched mapping files are checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Loads a mapping (mode name or path) and builds its stage, returning the files it was read
+/// from.
+pub type StageLoader = Box<dyn FnMut(&str) -> Result<(Stage, Vec<PathBuf>), String>>;
+
+/// Each watched file and its modification time when last checked (`None` if missing).
+type Stamps = Vec<(PathBuf, Option<SystemTime>)>;
+
+/// A loaded mapping whose stage hasn't been confirmed yet.
+struct Pending {
+    mapping: String,
+    files: Stamps,
+    /// The file whose change triggered a reload; `None` for a switch
+    changed: Option<PathBuf>,
+}
+
+/// Reloads the mapping when one of its files (the mapping or anything it extends) changes.
+/// A mapping that fails to load is reported and the running one is kept.
+pub struct MappingWatcher {
+    load: StageLoader,
+    /// The running mapping, as given to `load`
+    mapping: String,
+    files: Stamps,
+    next_check: Instant,
+    pending: Option<Pending>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn stamp(files: Vec<PathBuf>) -> Stamps {
+    files
+        .into_iter()
+        .map(|path| {
+            let seen = modified(&path);
+            (path, seen)
+        })
+        .collect()
+}
+
+impl MappingWatcher {
+    /// Watch `mapping`, already loaded from `files`.
+    pub fn new(mapping: &str, files: Vec<PathBuf>, load: StageLoader) -> Self {
+        Self {
+            load,
+            mapping: mapping.to_string(),
+            files: stamp(files),
+            next_check: Instant::now() + CHECK_INTERVAL,
+            pending: None,
+        }
+    }
+
+    /// Load another mapping, watched instead once its stage is confirmed.
+    pub fn switch_to(&mut self, mapping: &str) -> Result<Stage, String> {
+        let (stage, files) = (self.load)(mapping)?;
+        self.pending = Some(Pending {
+            mapping: mapping.to_string(),
+            files: stamp(files),
+            changed: None,
+        });
+        Ok(stage)
+    }
+
+    /// The running mapping and the files watched for it.
+    #[cfg(test)]
+    pub fn watched(&self) -> (&str, Vec<PathBuf>) {
+        let files = self.files.iter().map(|(path, _)| path.clone()).collect();
+        (&self.mapping, files)
+    }
+}
+
+impl StageSource for MappingWatcher {
+    fn next_deadline(&self) -> Option<Instant> {
+        Some(self.next_check)
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<Stage> {
+        if now < self.next_check {
+            return None;
+        }
+        self.next_check = now + CHECK_INTERVAL;
+
+        let changed = self
+            .files
+            .iter()
+            .find(|(path, seen)| modified(path) != *seen)?
+            .0
+            .clone();
+        match (self.load)(&self.mapping) {
+            Ok((stage, files)) => {
+                self.pending = Some(Pending {
+                    mapping: self.mapping.clone(),
+                    files: stamp(files),
+                    changed: Some(changed),
+                });
+                Some(stage)
+            }
+            Err(e) => {
+                eprintln!("Mapping reload failed, keeping the previous mapping: {}", e);
+                // Wait for the next change instead of failing on every check
+                let files = self.files.drain(..).map(|(path, _)| path).collect();
+                self.files = stamp(files);
+                None
+            }
+        }
+    }
+
+    fn confirm(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        if let Some(changed) = pending.changed {
+            println!("Mapping reloaded ({} changed)", changed.display());
+        }
+        self.mapping = pending.mapping;
+        self.files = pending.files;
+    }
+
+    fn reject(&mut self) {
+        if self.pending.take().is_some_and(|p| p.changed.is_some()) {
+            // Wait for the next change instead of reloading on every check
+            let files = self.files.drain(..).map(|(path, _)| path).collect();
+            self.files = stamp(files);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::FilterChain;
+    use crate::mapping::load_watched_mapping;
+    use crate::outputs::mapper::ButtonMapper;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn write(path: &Path, text: &str, age_secs: u64) {
+        fs::write(path, text).unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age_secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn reloads_on_change_and_keeps_mapping_on_failure() {
+        let dir = std::env::temp_dir().join(format!("ps2iidx-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mine.json");
+        write(&path, "ok", 10);
+
+        let loads = Rc::new(Cell::new(0));
+        let counter = loads.clone();
+        let watched = path.clone();
+        let mut watcher = MappingWatcher::new(
+            "mine",
+            vec![path.clone()],
+            Box::new(move |name| {
+                assert_eq!(name, "mine");
+                counter.set(counter.get() + 1);
+                if fs::read_to_string(&watched).unwrap() != "ok" {
+                    return Err("broken".to_string());
+                }
+                let (mapping, _) = load_watched_mapping("mapping/iidx.keyboard.json", &[])?;
+                let stage = Stage {
+                    filters: FilterChain::new(),
+                    adapter: Box::new(ButtonMapper::new(&mapping, 0, false)),
+                    outputs: mapping.outputs(),
+                };
+                Ok((stage, vec![watched.clone()]))
+            }),
+        );
+        let mut at = Instant::now();
+        let mut check = |watcher: &mut MappingWatcher| {
+            at += CHECK_INTERVAL;
+            let reloaded = watcher.poll(at).is_some();
+            if reloaded {
+                watcher.confirm();
+            }
+            reloaded
+        };
+
+        assert!(!check(&mut watcher), "unchanged files are not reloaded");
+        assert_eq!(loads.get(), 0);
+
+        write(&path, "broken", 5);
+        assert!(
+            !check(&mut watcher),
+            "a failed load keeps the running stage"
+        );
+        assert!(
+            !check(&mut watcher),
+            "and is not retried until the next change"
+        );
+        assert_eq!(loads.get(), 1);
+
+        write(&path, "ok", 1);
+        assert!(check(&mut watcher));
+        assert_eq!(loads.get(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
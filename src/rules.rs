@@ -0,0 +1,402 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::filters::EventFilter;
+use crate::keys::KeyCode;
+use crate::types::{ButtonEntry, ButtonEvent, KeyboardSpecialConfig, RuleAction, RuleConfig};
+
+/// Tap length when a tap rule doesn't set one.
+const DEFAULT_TAP_MS: u64 = 13;
+
+/// Built-in rule sets, selected with `"rules": "<name>"` in a mapping.
+pub fn preset(name: &str) -> Option<Vec<RuleConfig>> {
+    match name {
+        // The original iidx `special` behaviour: while the effector button (F14) is held,
+        // turntable buttons tap, and any press lets go of a turntable key held from before
+        "iidx-legacy" => Some(vec![
+            RuleConfig {
+                while_held: vec![5, 10],
+                buttons: vec![4, 6],
+                action: RuleAction::Tap {
+                    ms: Some(DEFAULT_TAP_MS),
+                },
+            },
+            RuleConfig {
+                while_held: vec![5, 10],
+                buttons: vec![4, 6],
+                action: RuleAction::ReleaseOnPress,
+            },
+        ]),
+        _ => None,
+    }
+}
+
+pub fn preset_names() -> &'static [&'static str] {
+    &["iidx-legacy"]
+}
+
+/// Express a keyboard mapping's legacy `special` block as rules over the buttons mapped to
+/// its keys.
+pub fn from_special(
+    special: &KeyboardSpecialConfig,
+    buttons: &HashMap<String, ButtonEntry>,
+) -> Vec<RuleConfig> {
+    let buttons_for = |keys: &[KeyCode]| -> Vec<u8> {
+        let mut ids: Vec<u8> = buttons
+            .iter()
+            .filter(|(_, entry)| entry.keys().any(|key| keys.contains(&key)))
+            .filter_map(|(id, _)| id.parse().ok())
+            .collect();
+        ids.sort();
+        ids
+    };
+
+    // Without an ignore key, tap keys were plain keys
+    let Some(ignore_key) = special.ignore_key else {
+        return Vec::new();
+    };
+    let while_held = buttons_for(&[ignore_key]);
+
+    let mut rules = Vec::new();
+    if let Some(tap_keys) = &special.tap_keys {
+        rules.push(RuleConfig {
+            while_held: while_held.clone(),
+            buttons: buttons_for(tap_keys),
+            action: RuleAction::Tap {
+                ms: Some(special.tap_duration_ms.unwrap_or(DEFAULT_TAP_MS)),
+            },
+        });
+    }
+    if let Some(release_keys) = &special.release_on_ignore {
+        rules.push(RuleConfig {
+            while_held,
+            buttons: buttons_for(release_keys),
+            action: RuleAction::ReleaseOnPress,
+        });
+    }
+    rules
+}
+
+fn release_event(id: u8, at: Instant) -> ButtonEvent {
+    ButtonEvent {
+        id,
+        pressed: false,
+        at,
+    }
+}
+
+/// What a held button was turned into when it was pressed, so its release matches
+/// even if the rules' conditions changed in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Active {
+    /// Passed on as this button id
+    Pressed(u8),
+    /// Passed on as a tap that releases itself
+    Tapped,
+    /// Swallowed, or already released by a rule
+    Silent,
+}
+
+/// Applies mapping rules of the form "while X is held, button Y behaves as ...".
+///
+/// Conditions are checked when a button is pressed; its release always undoes exactly
+/// what its press did. A button never triggers rules on its own press.
+pub struct RuleEngine {
+    rules: Vec<RuleConfig>,
+    held: HashSet<u8>,
+    active: HashMap<u8, Active>,
+    /// Pending tap releases: (button, deadline)
+    taps: Vec<(u8, Instant)>,
+    debug: bool,
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<RuleConfig>, debug: bool) -> Self {
+        Self {
+            rules,
+            held: HashSet::new(),
+            active: HashMap::new(),
+            taps: Vec::new(),
+            debug,
+        }
+    }
+
+    fn applies(&self, rule: &RuleConfig, button: u8) -> bool {
+        rule.buttons.contains(&button) && rule.while_held.iter().any(|id| self.held.contains(id))
+    }
+
+    fn press(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        // Let go of held targets of every active release-on-press rule
+        let to_release: Vec<u8> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.action == RuleAction::ReleaseOnPress)
+            .filter(|rule| rule.while_held.iter().any(|id| self.held.contains(id)))
+            .flat_map(|rule| rule.buttons.iter().copied())
+            .collect();
+        for target in to_release {
+            match self.active.get(&target).copied() {
+                Some(Active::Pressed(id)) => out.push(release_event(id, event.at)),
+                Some(Active::Tapped) if self.taps.iter().any(|(b, _)| *b == target) => {
+                    self.taps.retain(|(b, _)| *b != target);
+                    out.push(release_event(target, event.at));
+                }
+                _ => continue,
+            }
+            if self.debug {
+                println!(
+                    "[rules] button {} released by press of {}",
+                    target, event.id
+                );
+            }
+            self.active.insert(target, Active::Silent);
+        }
+
+        let action = self
+            .rules
+            .iter()
+            .filter(|rule| rule.action != RuleAction::ReleaseOnPress)
+            .find(|rule| self.applies(rule, event.id))
+            .map(|rule| rule.action.clone());
+
+        let active = match action {
+            Some(RuleAction::Suppress) => {
+                if self.debug {
+                    println!("[rules] button {} suppressed", event.id);
+                }
+                Active::Silent
+            }
+            Some(RuleAction::Map { to }) => {
+                if self.debug {
+                    println!("[rules] button {} mapped to {}", event.id, to);
+                }
+                out.push(ButtonEvent { id: to, ..event });
+                Active::Pressed(to)
+            }
+            Some(RuleAction::Tap { ms }) => {
+                let duration = Duration::from_millis(ms.unwrap_or(DEFAULT_TAP_MS));
+                self.taps.push((event.id, event.at + duration));
+                out.push(event.clone());
+                Active::Tapped
+            }
+            Some(RuleAction::ReleaseOnPress) | None => {
+                out.push(event.clone());
+                Active::Pressed(event.id)
+            }
+        };
+        self.active.insert(event.id, active);
+        self.held.insert(event.id);
+    }
+
+    fn release_button(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        self.held.remove(&event.id);
+        match self.active.remove(&event.id) {
+            Some(Active::Pressed(id)) => out.push(ButtonEvent { id, ..event }),
+            // Released before the tap ended: end it now
+            Some(Active::Tapped) if self.taps.iter().any(|(b, _)| *b == event.id) => {
+                self.taps.retain(|(b, _)| *b != event.id);
+                out.push(event);
+            }
+            Some(_) => {}
+            // Pressed before the engine started; pass the release on
+            None => out.push(event),
+        }
+    }
+}
+
+impl EventFilter for RuleEngine {
+    fn on_event(&mut self, event: ButtonEvent, out: &mut Vec<ButtonEvent>) {
+        // Taps that ended before this event go out first
+        self.poll(event.at, out);
+        if event.pressed {
+            self.press(event, out);
+        } else {
+            self.release_button(event, out);
+        }
+    }
+
+    fn poll(&mut self, now: Instant, out: &mut Vec<ButtonEvent>) {
+        let mut due: Vec<(u8, Instant)> = Vec::new();
+        self.taps.retain(|&(button, at)| {
+            if at <= now {
+                due.push((button, at));
+                false
+            } else {
+                true
+            }
+        });
+        due.sort_by_key(|&(_, at)| at);
+        for (button, at) in due {
+            out.push(release_event(button, at));
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.taps.iter().map(|&(_, at)| at).min()
+    }
+
+    fn reset(&mut self) {
+        self.held.clear();
+        self.active.clear();
+        self.taps.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OutputTarget;
+
+    fn event(id: u8, pressed: bool, start: Instant, ms: u64) -> ButtonEvent {
+        ButtonEvent {
+            id,
+            pressed,
+            at: start + Duration::from_millis(ms),
+        }
+    }
+
+    fn edges(events: &[ButtonEvent]) -> Vec<(u8, bool)> {
+        events.iter().map(|e| (e.id, e.pressed)).collect()
+    }
+
+    fn iidx_legacy() -> RuleEngine {
+        RuleEngine::new(preset("iidx-legacy").unwrap(), false)
+    }
+
+    #[test]
+    fn buttons_pass_through_without_active_rules() {
+        let mut rules = iidx_legacy();
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        rules.on_event(event(4, true, start, 0), &mut out);
+        rules.on_event(event(4, false, start, 50), &mut out);
+        assert_eq!(edges(&out), vec![(4, true), (4, false)]);
+        assert_eq!(rules.next_deadline(), None);
+    }
+
+    #[test]
+    fn reset_forgets_held_conditions_and_pending_taps() {
+        let mut rules = iidx_legacy();
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        rules.on_event(event(5, true, start, 0), &mut out);
+        rules.on_event(event(4, true, start, 1), &mut out);
+        assert!(rules.next_deadline().is_some());
+
+        // The link dropped with the effector held; its release never arrives
+        rules.reset();
+        assert_eq!(rules.next_deadline(), None);
+        out.clear();
+        rules.on_event(event(6, true, start, 100), &mut out);
+        rules.on_event(event(6, false, start, 200), &mut out);
+        assert_eq!(edges(&out), vec![(6, true), (6, false)]);
+        assert_eq!(out[1].at, start + Duration::from_millis(200));
+    }
+
+    #[test]
+    fn tap_rule_releases_after_tap_duration() {
+        let mut rules = iidx_legacy();
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        rules.on_event(event(5, true, start, 0), &mut out);
+        rules.on_event(event(4, true, start, 1), &mut out);
+        assert_eq!(
+            rules.next_deadline(),
+            Some(start + Duration::from_millis(14))
+        );
+
+        rules.poll(start + Duration::from_millis(14), &mut out);
+        // The button's own release after the tap must not release it a second time
+        rules.on_event(event(4, false, start, 30), &mut out);
+        assert_eq!(edges(&out), vec![(5, true), (4, true), (4, false)]);
+        assert_eq!(out[2].at, start + Duration::from_millis(14));
+    }
+
+    #[test]
+    fn early_release_ends_tap() {
+        let mut rules = iidx_legacy();
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        rules.on_event(event(10, true, start, 0), &mut out);
+        rules.on_event(event(6, true, start, 1), &mut out);
+        rules.on_event(event(6, false, start, 5), &mut out);
+        assert_eq!(edges(&out), vec![(10, true), (6, true), (6, false)]);
+        assert_eq!(rules.next_deadline(), None);
+    }
+
+    #[test]
+    fn press_releases_targets_held_from_before() {
+        let mut rules = iidx_legacy();
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        rules.on_event(event(4, true, start, 0), &mut out);
+        rules.on_event(event(5, true, start, 10), &mut out);
+        rules.on_event(event(1, true, start, 20), &mut out);
+        rules.on_event(event(4, false, start, 30), &mut out);
+        assert_eq!(
+            edges(&out),
+            vec![(4, true), (5, true), (4, false), (1, true)]
+        );
+    }
+
+    #[test]
+    fn map_and_suppress_undo_on_release_after_condition_ends() {
+        let config = vec![
+            RuleConfig {
+                while_held: vec![0],
+                buttons: vec![1],
+                action: RuleAction::Map { to: 9 },
+            },
+            RuleConfig {
+                while_held: vec![0],
+                buttons: vec![2],
+                action: RuleAction::Suppress,
+            },
+        ];
+        let mut rules = RuleEngine::new(config, false);
+        let start = Instant::now();
+        let mut out = Vec::new();
+
+        rules.on_event(event(0, true, start, 0), &mut out);
+        rules.on_event(event(1, true, start, 1), &mut out);
+        rules.on_event(event(2, true, start, 2), &mut out);
+        rules.on_event(event(0, false, start, 3), &mut out);
+        rules.on_event(event(1, false, start, 4), &mut out);
+        rules.on_event(event(2, false, start, 5), &mut out);
+        assert_eq!(
+            edges(&out),
+            vec![(0, true), (9, true), (0, false), (9, false)]
+        );
+    }
+
+    #[test]
+    fn legacy_iidx_special_matches_preset() {
+        let special = KeyboardSpecialConfig {
+            ignore_key: Some(KeyCode::Function(14)),
+            tap_keys: Some(vec![KeyCode::Function(13), KeyCode::Function(15)]),
+            tap_duration_ms: Some(13),
+            release_on_ignore: Some(vec![KeyCode::Function(13), KeyCode::Function(15)]),
+        };
+        let buttons: HashMap<String, ButtonEntry> =
+            [(4, "F13"), (5, "F14"), (6, "F15"), (10, "F14"), (11, "F20")]
+                .into_iter()
+                .map(|(id, key)| {
+                    let outputs = vec![OutputTarget::Key(KeyCode::parse(key).unwrap())];
+                    let entry = ButtonEntry {
+                        outputs,
+                        ..Default::default()
+                    };
+                    (id.to_string(), entry)
+                })
+                .collect();
+        assert_eq!(
+            from_special(&special, &buttons),
+            preset("iidx-legacy").unwrap()
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::Instant;
+
+/// Deadline-ordered queue of pending output actions.
+/// Actions with the same deadline come out in the order they were scheduled.
+pub struct Scheduler<T> {
+    queue: Vec<(Instant, T)>,
+}
+
+impl<T> Scheduler<T> {
+    pub fn new() -> Self {
+        Self { queue: Vec::new() }
+    }
+
+    pub fn schedule(&mut self, at: Instant, item: T) {
+        // Insert after every entry due at or before `at` to keep FIFO order for ties
+        let index = self.queue.partition_point(|(deadline, _)| *deadline <= at);
+        self.queue.insert(index, (at, item));
+    }
+
+    /// Remove and return every action due at `now`, in deadline order.
+    pub fn take_due(&mut self, now: Instant) -> Vec<(Instant, T)> {
+        let count = self.queue.partition_point(|(deadline, _)| *deadline <= now);
+        self.queue.drain(..count).collect()
+    }
+
+    /// Remove and return every pending action, due or not, in deadline order.
+    pub fn take_all(&mut self) -> Vec<(Instant, T)> {
+        std::mem::take(&mut self.queue)
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.first().map(|(deadline, _)| *deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn takes_only_due_actions_in_deadline_order() {
+        let mut scheduler = Scheduler::new();
+        let start = Instant::now();
+
+        scheduler.schedule(start + Duration::from_millis(20), "late");
+        scheduler.schedule(start + Duration::from_millis(10), "early");
+        assert_eq!(
+            scheduler.next_deadline(),
+            Some(start + Duration::from_millis(10))
+        );
+
+        let due = scheduler.take_due(start + Duration::from_millis(15));
+        assert_eq!(due, vec![(start + Duration::from_millis(10), "early")]);
+
+        let due = scheduler.take_due(start + Duration::from_millis(20));
+        assert_eq!(due, vec![(start + Duration::from_millis(20), "late")]);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn equal_deadlines_keep_schedule_order() {
+        let mut scheduler = Scheduler::new();
+        let at = Instant::now() + Duration::from_millis(10);
+
+        for item in 0..7 {
+            scheduler.schedule(at, item);
+        }
+        let due: Vec<i32> = scheduler.take_due(at).into_iter().map(|(_, i)| i).collect();
+        assert_eq!(due, vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn take_all_keeps_order() {
+        let mut scheduler = Scheduler::new();
+        let start = Instant::now();
+
+        scheduler.schedule(start + Duration::from_secs(10), 'b');
+        scheduler.schedule(start, 'a');
+        let all: Vec<char> = scheduler.take_all().into_iter().map(|(_, c)| c).collect();
+        assert_eq!(all, vec!['a', 'b']);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+}
@@ -1,5 +1,7 @@
 use std::io::{BufRead, BufReader};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::types::ButtonEvent;
 
@@ -7,19 +9,20 @@ pub struct SerialOptions {
     pub path: String,
     pub baud_rate: u32,
     pub debug: bool,
+    /// The loop returns once this is cleared (e.g. by the Ctrl+C handler).
+    pub running: Arc<AtomicBool>,
 }
 
-/// Event passed to the handler on each loop iteration.
+/// Event passed to the handler by the serial loop.
 pub enum SerialEvent {
     /// A valid button event was received.
     Button(ButtonEvent),
-    /// Called every loop iteration (before reading) for housekeeping (e.g. tap releases).
-    Tick,
+    /// Reading from the port failed; buttons held on the controller can't be trusted anymore.
+    LinkLost,
 }
 
-/// Open a serial port and run the event loop.
-/// The single `handler` callback receives both Tick and Button events,
-/// avoiding borrow conflicts from having two separate closures.
+/// Open a serial port and run the event loop until `options.running` is cleared or the port closes.
+/// Runs on the pipeline's reader thread; `handler` should only hand events on.
 pub fn run_serial_loop<F>(options: &SerialOptions, mut handler: F) -> Result<(), String>
 where
     F: FnMut(SerialEvent),
@@ -40,15 +43,21 @@ where
     // Opening the serial port asserts DTR which triggers Arduino's auto-reset circuit.
     // We wait 2 seconds for the bootloader to finish and the sketch to start.
     println!("Waiting for Arduino to boot...");
-    std::thread::sleep(Duration::from_secs(2));
+    let boot_deadline = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < boot_deadline {
+        if !options.running.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
 
     println!("Listening for input...");
 
     let mut reader = BufReader::new(port);
     let mut line = String::new();
+    let mut link_up = true;
 
-    loop {
-        handler(SerialEvent::Tick);
+    while options.running.load(Ordering::SeqCst) {
         line.clear();
 
         match reader.read_line(&mut line) {
@@ -57,6 +66,8 @@ where
                 break;
             }
             Ok(_) => {
+                link_up = true;
+                let received_at = Instant::now();
                 let trimmed = line.trim();
                 if trimmed.is_empty() {
                     continue;
@@ -91,6 +102,7 @@ where
                 handler(SerialEvent::Button(ButtonEvent {
                     id: button_id,
                     pressed: state == "1",
+                    at: received_at,
                 }));
             }
             Err(e) => {
@@ -98,6 +110,10 @@ where
                     continue;
                 }
                 eprintln!("Error reading from serial port: {:?}", e);
+                if link_up {
+                    link_up = false;
+                    handler(SerialEvent::LinkLost);
+                }
             }
         }
     }
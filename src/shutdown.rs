@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long the main loop gets to shut down cleanly after Ctrl+C
+/// before the handler releases held outputs itself and exits.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+type ReleaseFn = Box<dyn Fn() + Send>;
+
+/// Last-resort release callbacks and their registration ids, run on panic or when a clean
+/// shutdown doesn't happen in time.
+static EMERGENCY_RELEASES: Mutex<Vec<(u64, ReleaseFn)>> = Mutex::new(Vec::new());
+
+static NEXT_REGISTRATION: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps an emergency release callback registered; dropping it unregisters the callback.
+pub struct Registration {
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut releases = EMERGENCY_RELEASES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        releases.retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Register a callback that releases everything an output currently holds, for as long as
+/// the returned registration is kept.
+/// It may run on any thread, so it must not rely on the output's own device handle.
+pub fn on_emergency_release<F>(release: F) -> Registration
+where
+    F: Fn() + Send + 'static,
+{
+    let id = NEXT_REGISTRATION.fetch_add(1, Ordering::Relaxed);
+    let mut releases = EMERGENCY_RELEASES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    releases.push((id, Box::new(release)));
+    Registration { id }
+}
+
+/// Run every registered release callback.
+pub fn emergency_release() {
+    // try_lock: a panic while registering must not deadlock the panic hook
+    let releases = match EMERGENCY_RELEASES.try_lock() {
+        Ok(guard) => guard,
+        Err(std::sync::TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(std::sync::TryLockError::WouldBlock) => return,
+    };
+    for (_, release) in releases.iter() {
+        release();
+    }
+}
+
+/// Release held outputs before the default panic message is printed.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        emergency_release();
+        default_hook(info);
+    }));
+}
+
+/// Clear `running` on Ctrl+C so the main loop can shut down cleanly.
+/// If the loop is stuck (e.g. blocked in a read) or Ctrl+C is pressed again,
+/// held outputs are released from the handler thread and the process exits.
+pub fn install_ctrlc_handler(running: Arc<AtomicBool>) -> Result<(), String> {
+    ctrlc::set_handler(move || {
+        if running.swap(false, Ordering::SeqCst) {
+            println!("\nClosing...");
+            std::thread::spawn(|| {
+                std::thread::sleep(SHUTDOWN_GRACE);
+                emergency_release();
+                std::process::exit(0);
+            });
+        } else {
+            emergency_release();
+            std::process::exit(130);
+        }
+    })
+    .map_err(|e| format!("Failed to set Ctrl+C handler: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn dropped_registrations_no_longer_run() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let registration = on_emergency_release(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        emergency_release();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        drop(registration);
+        emergency_release();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
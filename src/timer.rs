@@ -0,0 +1,116 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+
+/// Further than this from the deadline, block on the channel and let the OS timer wake us.
+/// OS timers can overshoot by a whole scheduler tick (~15.6 ms on Windows by default).
+const COARSE_MARGIN: Duration = Duration::from_millis(20);
+
+/// Closer than this, busy-wait instead of sleeping.
+const SPIN_MARGIN: Duration = Duration::from_micros(1500);
+
+/// Longest single sleep while approaching a deadline, so new messages are still picked up quickly.
+const SLEEP_STEP: Duration = Duration::from_millis(1);
+
+/// Why `recv_until` returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Wake<T> {
+    Message(T),
+    Deadline,
+    Disconnected,
+}
+
+/// How `recv_until` waits for the rest of the time until a deadline.
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    /// Block on the channel for this long.
+    Block(Duration),
+    /// Sleep this long, then check the channel again.
+    Sleep(Duration),
+    Spin,
+}
+
+fn step(remaining: Duration) -> Step {
+    if remaining > COARSE_MARGIN {
+        Step::Block(remaining - COARSE_MARGIN)
+    } else if remaining > SPIN_MARGIN {
+        Step::Sleep(SLEEP_STEP.min(remaining - SPIN_MARGIN))
+    } else {
+        Step::Spin
+    }
+}
+
+/// Wait for the next message on `rx`, or until `deadline` passes.
+/// Deadlines are hit with sub-millisecond accuracy by sleeping in small steps
+/// and spinning for the last stretch, independent of any read timeout.
+pub fn recv_until<T>(rx: &Receiver<T>, deadline: Option<Instant>) -> Wake<T> {
+    loop {
+        match rx.try_recv() {
+            Ok(message) => return Wake::Message(message),
+            Err(TryRecvError::Disconnected) => return Wake::Disconnected,
+            Err(TryRecvError::Empty) => {}
+        }
+
+        let Some(deadline) = deadline else {
+            return match rx.recv() {
+                Ok(message) => Wake::Message(message),
+                Err(_) => Wake::Disconnected,
+            };
+        };
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Wake::Deadline;
+        }
+
+        match step(deadline - now) {
+            Step::Block(timeout) => match rx.recv_timeout(timeout) {
+                Ok(message) => return Wake::Message(message),
+                Err(RecvTimeoutError::Disconnected) => return Wake::Disconnected,
+                Err(RecvTimeoutError::Timeout) => {}
+            },
+            Step::Sleep(duration) => std::thread::sleep(duration),
+            Step::Spin => std::hint::spin_loop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn returns_pending_message_first() {
+        let (tx, rx) = mpsc::channel();
+        tx.send(7).unwrap();
+        assert_eq!(recv_until(&rx, Some(Instant::now())), Wake::Message(7));
+    }
+
+    #[test]
+    fn blocks_then_sleeps_then_spins_toward_deadline() {
+        let ms = Duration::from_millis;
+        assert_eq!(step(ms(100)), Step::Block(ms(80)));
+        assert_eq!(step(ms(20)), Step::Sleep(ms(1)));
+        assert_eq!(step(ms(2)), Step::Sleep(Duration::from_micros(500)));
+        assert_eq!(step(Duration::from_micros(1500)), Step::Spin);
+        assert_eq!(step(Duration::ZERO), Step::Spin);
+    }
+
+    #[test]
+    fn wakes_close_to_deadline() {
+        let (_tx, rx) = mpsc::channel::<()>();
+        let deadline = Instant::now() + Duration::from_millis(13);
+
+        assert_eq!(recv_until(&rx, Some(deadline)), Wake::Deadline);
+        // Loose bound: the test harness runs tests in parallel, and CI runners may be loaded
+        let late = Instant::now() - deadline;
+        assert!(late < Duration::from_millis(5), "woke {:?} late", late);
+    }
+
+    #[test]
+    fn reports_disconnect() {
+        let (tx, rx) = mpsc::channel::<()>();
+        drop(tx);
+        assert_eq!(recv_until(&rx, None), Wake::Disconnected);
+    }
+}
@@ -1,5 +1,9 @@
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
 use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::keys::KeyCode;
 
 // --- Button Event ---
 
@@ -7,6 +11,8 @@ use std::collections::HashMap;
 pub struct ButtonEvent {
     pub id: u8,
     pub pressed: bool,
+    /// When the event was read from the serial port
+    pub at: Instant,
 }
 
 // --- Output Type ---
@@ -18,33 +24,167 @@ pub enum OutputType {
     X360,
 }
 
-// --- Keyboard Mapping ---
+// --- Event Filters ---
 
+/// One stage of a mapping's `"filters"` list, applied to button events in order.
 #[derive(Debug, Clone, Deserialize)]
-pub struct KeyboardButtonEntry {
-    pub key: String,
+#[serde(
+    tag = "type",
+    rename_all = "lowercase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+pub enum FilterConfig {
+    /// Send a button's events as another button id.
+    Remap {
+        #[serde(deserialize_with = "button_id_keys")]
+        buttons: HashMap<u8, u8>,
+    },
+    /// Suppress switch chatter, with a default window and optional per-button windows.
+    Debounce {
+        #[serde(default)]
+        mode: DebounceMode,
+        #[serde(default)]
+        ms: u64,
+        #[serde(default, deserialize_with = "button_id_keys")]
+        buttons: HashMap<u8, u64>,
+    },
+    /// Keep presses down for at least `ms` (or a per-button time) by deferring early releases.
+    #[serde(rename = "minHold")]
+    MinHold {
+        #[serde(default)]
+        ms: u64,
+        #[serde(default, deserialize_with = "button_id_keys")]
+        buttons: HashMap<u8, u64>,
+    },
+    /// Decide what opposing buttons do when both are held.
+    Socd {
+        #[serde(default)]
+        mode: SocdMode,
+        pairs: Vec<(u8, u8)>,
+    },
+    /// Hold a turntable direction while its pulses keep coming.
+    Turntable {
+        up: u8,
+        down: u8,
+        idle_ms: Option<u64>,
+        /// Buttons that, while held, let each pulse through as its own press
+        #[serde(default)]
+        pass_while_held: Vec<u8>,
+    },
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DebounceMode {
+    /// Pass a change immediately, then ignore chatter for the window
+    #[default]
+    Eager,
+    /// Pass a change once it has been stable for the window
+    Deferred,
+}
+
+/// What a pair of opposing buttons does while both are held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct KeyboardSpecialConfig {
-    pub ignore_key: Option<String>,
-    pub tap_keys: Option<Vec<String>>,
-    pub tap_duration_ms: Option<u64>,
-    pub release_on_ignore: Option<Vec<String>>,
+pub enum SocdMode {
+    /// The most recent press wins; letting go of it brings the other back
+    #[default]
+    LastWins,
+    /// The button held first stays until it is let go
+    FirstWins,
+    /// Neither
+    Neutral,
+    /// The first button of the pair
+    Priority,
+}
+
+/// Deserialize an object keyed by button id (`{ "12": ... }`) into numeric keys.
+fn button_id_keys<'de, D, V>(deserializer: D) -> Result<HashMap<u8, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    HashMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(id, value)| {
+            id.parse()
+                .map(|id| (id, value))
+                .map_err(|_| de::Error::custom(format!("invalid button id \"{}\"", id)))
+        })
+        .collect()
+}
+
+// --- Rules ---
+
+/// "While any of `while` is held, pressing one of `buttons` does `action`."
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RuleConfig {
+    #[serde(rename = "while")]
+    pub while_held: Vec<u8>,
+    pub buttons: Vec<u8>,
+    #[serde(flatten)]
+    pub action: RuleAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "do", rename_all = "camelCase")]
+pub enum RuleAction {
+    /// Press, then release after `ms` or when the button is released, whichever comes first
+    Tap { ms: Option<u64> },
+    /// Swallow the press and its release
+    Suppress,
+    /// Pass the press and its release on as button `to`
+    Map { to: u8 },
+    /// Any press first releases `buttons` if they are held
+    ReleaseOnPress,
 }
 
+// --- Layers ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerMode {
+    /// Active while the layer button is held
+    #[default]
+    Momentary,
+    /// Each press of the layer button switches the layer on or off
+    Toggle,
+}
+
+/// Alternative button entries, active while `button` holds or toggles the layer on.
 #[derive(Debug, Clone, Deserialize)]
-pub struct KeyboardMapping {
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Layer<E> {
     pub name: Option<String>,
-    pub output: OutputType,
-    pub buttons: HashMap<String, KeyboardButtonEntry>,
-    pub special: Option<KeyboardSpecialConfig>,
+    pub button: u8,
+    #[serde(default)]
+    pub mode: LayerMode,
+    /// Whether buttons missing from this layer use the layers below it and the base mapping
+    #[serde(default = "default_fallthrough")]
+    pub fallthrough: bool,
+    pub buttons: HashMap<String, E>,
+}
+
+fn default_fallthrough() -> bool {
+    true
 }
 
-// --- X360 Mapping ---
+// --- Legacy Keyboard Special ---
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+/// Legacy `special` block; converted to rules when the mapping loads.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyboardSpecialConfig {
+    pub ignore_key: Option<KeyCode>,
+    pub tap_keys: Option<Vec<KeyCode>>,
+    pub tap_duration_ms: Option<u64>,
+    pub release_on_ignore: Option<Vec<KeyCode>>,
+}
+
+// --- X360 Outputs ---
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum X360ButtonName {
     Start,
@@ -60,7 +200,7 @@ pub enum X360ButtonName {
     Y,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum DpadDirection {
     Up,
@@ -69,14 +209,14 @@ pub enum DpadDirection {
     Right,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TriggerName {
     Left,
     Right,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum X360ButtonEntry {
     Button { name: X360ButtonName },
@@ -84,40 +224,384 @@ pub enum X360ButtonEntry {
     Trigger { trigger: TriggerName },
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct X360Mapping {
+// --- Button Entries ---
+
+/// A single output a button can drive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OutputTarget {
+    Key(KeyCode),
+    Pad(X360ButtonEntry),
+}
+
+impl fmt::Display for OutputTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputTarget::Key(key) => write!(f, "{}", key),
+            OutputTarget::Pad(X360ButtonEntry::Button { name }) => write!(f, "{:?}", name),
+            OutputTarget::Pad(X360ButtonEntry::Dpad { direction }) => {
+                write!(f, "Dpad {:?}", direction)
+            }
+            OutputTarget::Pad(X360ButtonEntry::Trigger { trigger }) => {
+                write!(f, "{:?} trigger", trigger)
+            }
+        }
+    }
+}
+
+/// Everything one button drives, e.g. a key combination like Ctrl+F1.
+/// Outputs are pressed in order and released in reverse order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ButtonEntry {
+    pub outputs: Vec<OutputTarget>,
+    pub macros: Vec<Macro>,
+    /// Autofire for `outputs` instead of holding them
+    pub turbo: Option<Turbo>,
+    /// One press holds `outputs` down, the next lets them go
+    pub latch: bool,
+    /// Run when the entry is pressed
+    pub commands: Vec<Command>,
+    /// Separate actions for a tap, a hold and a double tap, instead of everything above
+    pub gestures: Option<Box<Gestures>>,
+    /// Extra delay for this entry's outputs in milliseconds; negative runs them early
+    pub offset_ms: i64,
+}
+
+impl ButtonEntry {
+    pub fn keys(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.outputs.iter().filter_map(|target| match target {
+            OutputTarget::Key(key) => Some(*key),
+            OutputTarget::Pad(_) => None,
+        })
+    }
+
+    fn targets(&self) -> impl Iterator<Item = &OutputTarget> {
+        self.outputs
+            .iter()
+            .chain(
+                self.macros
+                    .iter()
+                    .flat_map(|m| m.steps.iter())
+                    .filter_map(|step| match step {
+                        MacroStep::Down(target)
+                        | MacroStep::Up(target)
+                        | MacroStep::Tap(target) => Some(target),
+                        MacroStep::Text(_) | MacroStep::Delay(_) => None,
+                    }),
+            )
+    }
+
+    pub fn uses_keyboard(&self) -> bool {
+        self.targets()
+            .any(|target| matches!(target, OutputTarget::Key(_)))
+            || self.macros.iter().any(|m| {
+                m.steps
+                    .iter()
+                    .any(|step| matches!(step, MacroStep::Text(_)))
+            })
+            || self.gesture_entries().any(|e| e.uses_keyboard())
+    }
+
+    pub fn uses_pad(&self) -> bool {
+        self.targets()
+            .any(|target| matches!(target, OutputTarget::Pad(_)))
+            || self.gesture_entries().any(|e| e.uses_pad())
+    }
+
+    /// The tap, hold and double-tap entries, if any.
+    pub fn gesture_entries(&self) -> impl Iterator<Item = &ButtonEntry> {
+        self.gestures.iter().flat_map(|g| {
+            [&g.tap, &g.hold, &g.double_tap]
+                .into_iter()
+                .filter_map(Option::as_ref)
+        })
+    }
+}
+
+// --- Gestures ---
+
+/// Tap, hold and double-tap actions of one button.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gestures {
+    /// Pressed and released briefly when the button is let go before `hold_ms`
+    pub tap: Option<ButtonEntry>,
+    /// Held from `hold_ms` after the press until the button is released
+    pub hold: Option<ButtonEntry>,
+    /// Held while the second press of a double tap is
+    pub double_tap: Option<ButtonEntry>,
+    pub hold_ms: u64,
+    /// How long after a tap a second press counts as a double tap
+    pub double_tap_ms: u64,
+}
+
+// --- Commands ---
+
+/// Internal actions a button entry can trigger.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(
+    tag = "command",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+pub enum Command {
+    /// Let go of every output, latch, turbo and macro
+    ReleaseAll,
+    /// Switch a named layer on or off
+    ToggleLayer { layer: String },
+}
+
+// --- Macros ---
+
+/// When a macro starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroTrigger {
+    Press,
+    Release,
+    /// After the button has been held this long (ms)
+    LongPress(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroStep {
+    Down(OutputTarget),
+    Up(OutputTarget),
+    /// Press, hold for the macro's tap length, release and pause for as long again
+    Tap(OutputTarget),
+    /// Type text through the keyboard layout
+    Text(String),
+    /// Wait (ms)
+    Delay(u64),
+}
+
+/// A timed sequence of outputs started by a button.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub trigger: MacroTrigger,
+    pub tap_ms: u64,
+    pub steps: Vec<MacroStep>,
+}
+
+// --- Turbo ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurboMode {
+    /// Fires while the button is held
+    Hold,
+    /// Each press switches autofire on or off
+    Toggle,
+}
+
+/// Autofire: while active, outputs go down once every `period` and stay down for `down`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Turbo {
+    pub mode: TurboMode,
+    pub period: Duration,
+    pub down: Duration,
+}
+
+// --- Mapping Config ---
+
+#[derive(Debug, Clone)]
+pub struct MappingConfig {
     pub name: Option<String>,
+    /// The device the mapping is written for; entries may also drive the other one
     pub output: OutputType,
-    pub buttons: HashMap<String, X360ButtonEntry>,
+    pub buttons: HashMap<String, ButtonEntry>,
+    pub filters: Vec<FilterConfig>,
+    pub rules: Vec<RuleConfig>,
+    pub layers: Vec<Layer<ButtonEntry>>,
+    pub offsets: DeviceOffsets,
+    /// The side to play on unless the command line or environment picks one
+    pub side: Option<Side>,
+    /// How the 1P layout the mapping is written for turns into the 2P one
+    pub mirror: Option<Mirror>,
 }
 
-// --- Unified Mapping Config ---
+/// Which side of the cabinet the controller is played on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum Side {
+    #[default]
+    #[serde(rename = "1p")]
+    P1,
+    #[serde(rename = "2p")]
+    P2,
+}
 
-#[derive(Debug, Clone)]
-pub enum MappingConfig {
-    Keyboard(KeyboardMapping),
-    X360(X360Mapping),
+impl Side {
+    pub fn parse(name: &str) -> Result<Side, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "1p" => Ok(Side::P1),
+            "2p" => Ok(Side::P2),
+            _ => Err(format!("Side must be \"1p\" or \"2p\", got \"{}\"", name)),
+        }
+    }
 }
 
-impl MappingConfig {
-    pub fn name(&self) -> Option<&str> {
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            MappingConfig::Keyboard(m) => m.name.as_deref(),
-            MappingConfig::X360(m) => m.name.as_deref(),
+            Side::P1 => write!(f, "1p"),
+            Side::P2 => write!(f, "2p"),
+        }
+    }
+}
+
+/// The buttons that trade places between sides: `keys` (key 1 to 7 as seen from 1P) come in
+/// reverse order on 2P, and the two `turntable` directions swap.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mirror {
+    pub keys: Vec<u8>,
+    pub turntable: Option<(u8, u8)>,
+}
+
+impl Mirror {
+    /// The button renumbering that plays a 1P mapping on 2P.
+    pub fn remap(&self) -> HashMap<u8, u8> {
+        let keys = self.keys.iter().zip(self.keys.iter().rev());
+        let turntable = self
+            .turntable
+            .iter()
+            .flat_map(|&(up, down)| [(up, down), (down, up)]);
+        keys.map(|(&from, &to)| (from, to))
+            .chain(turntable)
+            .filter(|(from, to)| from != to)
+            .collect()
+    }
+}
+
+/// Extra delay per output device in milliseconds; negative runs that device early.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceOffsets {
+    pub keyboard: i64,
+    pub x360: i64,
+}
+
+impl DeviceOffsets {
+    pub fn of(&self, target: &OutputTarget) -> i64 {
+        match target {
+            OutputTarget::Key(_) => self.keyboard,
+            OutputTarget::Pad(_) => self.x360,
         }
     }
+}
+
+impl MappingConfig {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn filters(&self) -> &[FilterConfig] {
+        &self.filters
+    }
+
+    pub fn rules(&self) -> &[RuleConfig] {
+        &self.rules
+    }
 
     pub fn output_type(&self) -> &OutputType {
-        match self {
-            MappingConfig::Keyboard(m) => &m.output,
-            MappingConfig::X360(m) => &m.output,
+        &self.output
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &ButtonEntry> {
+        self.buttons
+            .values()
+            .chain(self.layers.iter().flat_map(|layer| layer.buttons.values()))
+    }
+
+    /// Whether the keyboard device is needed.
+    pub fn uses_keyboard(&self) -> bool {
+        self.output == OutputType::Keyboard || self.entries().any(|e| e.uses_keyboard())
+    }
+
+    /// Whether the virtual pad is needed.
+    pub fn uses_pad(&self) -> bool {
+        self.output == OutputType::X360 || self.entries().any(|e| e.uses_pad())
+    }
+
+    /// The output devices the mapping drives.
+    pub fn outputs(&self) -> Outputs {
+        Outputs {
+            keyboard: self.uses_keyboard(),
+            pad: self.uses_pad(),
         }
     }
+
+    /// Mirror the mapping for `side`, by renumbering buttons before any other filter.
+    pub fn play_on(&mut self, side: Side) -> Result<(), String> {
+        if side == Side::P1 {
+            return Ok(());
+        }
+        let mirror = self
+            .mirror
+            .as_ref()
+            .ok_or("Mapping has no \"mirror\" to play it on the 2p side")?;
+        let buttons = mirror.remap();
+        self.filters.insert(0, FilterConfig::Remap { buttons });
+        Ok(())
+    }
+
+    /// The most negative entry plus device offset of any output, or 0 if none is negative.
+    pub fn min_offset_ms(&self) -> i64 {
+        self.entries()
+            .flat_map(|e| std::iter::once(e).chain(e.gesture_entries()))
+            .filter(|e| e.gestures.is_none())
+            .flat_map(|e| {
+                let keyboard = e.uses_keyboard().then_some(self.offsets.keyboard);
+                let pad = e.uses_pad().then_some(self.offsets.x360);
+                keyboard.into_iter().chain(pad).map(|ms| ms + e.offset_ms)
+            })
+            .fold(0, i64::min)
+    }
+}
+
+// --- Output Actions ---
+
+/// A device-level action produced by an output adapter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputAction {
+    KeyDown(KeyCode),
+    KeyUp(KeyCode),
+    PadDown(X360ButtonEntry),
+    PadUp(X360ButtonEntry),
+    /// Type text through the keyboard layout
+    Text(String),
+}
+
+/// An action and when the output stage should perform it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedAction {
+    pub at: Instant,
+    pub action: OutputAction,
 }
 
 // --- Output Adapter Trait ---
 
+/// Mapping logic for one output type, run in the processing stage.
+/// Turns button events into timed device actions without touching a device.
 pub trait OutputAdapter {
-    fn handle_button(&mut self, event: &ButtonEvent);
+    fn handle_button(&mut self, event: &ButtonEvent, out: &mut Vec<TimedAction>);
+    /// Emit scheduled actions (e.g. tap releases) that are due at `now`.
+    fn poll(&mut self, now: Instant, out: &mut Vec<TimedAction>);
+    /// When `poll` next has something to do.
+    fn next_deadline(&self) -> Option<Instant>;
+    /// Release every output this adapter is holding.
+    fn release_all(&mut self, now: Instant, out: &mut Vec<TimedAction>);
+}
+
+// --- Output Device Trait ---
+
+/// Which output devices are needed: the keyboard, the virtual pad or both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outputs {
+    pub keyboard: bool,
+    pub pad: bool,
+}
+
+/// A virtual input device, driven by the output stage.
+pub trait OutputDevice {
+    fn execute(&mut self, action: &OutputAction);
+    /// Release anything still held and detach the device.
     fn shutdown(&mut self);
 }